@@ -56,7 +56,7 @@ unsafe extern "C" fn qbdi_instruction_callback(
 }
 
 fn solve_hashcash_wrapper(
-    lib: &JNI, client_hello: &Array, ap_response: &Array, prefix: &[u8], length: u8, target: u32,
+    lib: &mut JNI, client_hello: &Array, ap_response: &Array, prefix: &[u8], length: u8, target: u32,
 ) -> Vec<u8> {
     #[cfg(target_arch = "x86_64")]
     let pow_solve_hashcash: PowSolveHashcash = unsafe { std::mem::transmute(lib.get_offset(0x000000000122609c)) };
@@ -87,9 +87,9 @@ fn solve_hashcash_wrapper(
                 let mut fakestack = std::ptr::null_mut();
                 qbdi_allocateVirtualStack(vm_state, 0x100000, &mut fakestack);
                 qbdi_addCodeCB(vm, InstPosition_QBDI_PREINST, Some(qbdi_instruction_callback), std::ptr::null_mut(), 0);
-                let lib_start = lib.mapping.base as u64;
-                let lib_end = lib_start + lib.mapping.size as u64;
-                qbdi_addInstrumentedRange(vm, lib_start, lib_end);
+                for segment in lib.layout().segments.iter().filter(|s| s.executable) {
+                    qbdi_addInstrumentedRange(vm, segment.start as u64, segment.end as u64);
+                }
                 let mut suffix_len = 0;
                 qbdi_call(
                     vm,
@@ -142,7 +142,7 @@ fn main() -> Result<()> {
     let target = 4411;
     let target_suffix = hex::decode("0555aff2840bee9600000000000049d5")?;
 
-    let suffix = solve_hashcash_wrapper(&lib, &client_hello, &ap_response, &prefix, length, target);
+    let suffix = solve_hashcash_wrapper(&mut lib, &client_hello, &ap_response, &prefix, length, target);
 
     println!("Suffix len: {}", suffix.len());
     println!("Suffix: {}", hex::encode(&suffix));
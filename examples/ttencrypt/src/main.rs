@@ -3,7 +3,7 @@ use std::env;
 
 use anyhow::Result;
 use jni::{objects::JByteArray, InitArgsBuilder, JavaVM};
-use jni_loader::JNI;
+use jni_loader::{libc_overrides, JNI};
 
 // Class:     com_bytedance_frameworks_encryptor_EncryptorUtil
 // Method:    ttEncrypt
@@ -41,13 +41,13 @@ fn main() -> Result<()> {
     lib.add_dependency("libstdc++.so", None);
     lib.add_dependency("libdl.so", None);
     lib.load_dependencies().unwrap();
-    lib.override_symbol("srand", Some(libc::srand as *const ()));
-    lib.override_symbol("time", Some(libc::time as *const ()));
-    lib.override_symbol("rand", Some(libc::rand as *const ()));
-    lib.override_symbol("malloc", Some(libc::malloc as *const ()));
-    lib.override_symbol("memcpy", Some(libc::memcpy as *const ()));
-    lib.override_symbol("memset", Some(libc::memset as *const ()));
-    lib.override_symbol("free", Some(libc::free as *const ()));
+    lib.override_symbols(&[
+        ("srand", Some(libc::srand as *const ())),
+        ("time", Some(libc::time as *const ())),
+        ("rand", Some(libc::rand as *const ())),
+    ]);
+    lib.override_symbols(&libc_overrides::alloc_funcs());
+    lib.override_symbols(&libc_overrides::mem_funcs());
     lib.initialize()?;
 
     let tt_encrypt: TTEncrypt = unsafe { std::mem::transmute(lib.get_offset(0x7d8c)) };
@@ -31,3 +31,16 @@ if (jni_loader_lib_loaded_exp !== undefined) {
         }
     });
 }
+
+const jni_loader_lib_unloaded_exp = Process.mainModule.enumerateExports().find(exp => exp.name === "jni_loader_lib_unloaded");
+if (jni_loader_lib_unloaded_exp !== undefined) {
+    Interceptor.attach(jni_loader_lib_unloaded_exp.address, {
+        onEnter: function (args) {
+            const base = args[0];
+            const name = args[1].readCString() || "";
+            if (globalThis.onMappedLibUnload) {
+                globalThis.onMappedLibUnload(base, name);
+            }
+        }
+    });
+}
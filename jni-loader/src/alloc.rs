@@ -0,0 +1,124 @@
+use std::{
+    ffi::{c_int, c_void},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use nix::libc;
+
+// The wrappers are plain functions so they can't carry any context, the counters are shared by every library tracking
+// allocations. Sizes are the host allocator's usable size, so realloc and free subtract exactly what was added
+static TOTAL: AtomicUsize = AtomicUsize::new(0);
+static OUTSTANDING: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Heap usage of libraries whose allocator calls are tracked, see JNI::track_allocations
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct AllocStats {
+    pub total_allocated: usize, // Bytes allocated since tracking started or was reset, never decreases
+    pub outstanding: usize,     // Bytes allocated and not yet freed
+    pub peak: usize,            // Highest value outstanding has reached
+    pub allocation_count: usize, // Successful malloc, calloc, realloc and posix_memalign calls
+}
+
+/// Handle to the allocation counters. Copies all read the same process wide counters
+#[derive(Debug, Clone, Copy)]
+pub struct AllocTracker {
+    _private: (),
+}
+
+impl AllocTracker {
+    pub(crate) fn new() -> Self {
+        Self { _private: () }
+    }
+
+    pub fn stats(&self) -> AllocStats {
+        AllocStats {
+            total_allocated: TOTAL.load(Ordering::Relaxed),
+            outstanding: OUTSTANDING.load(Ordering::Relaxed),
+            peak: PEAK.load(Ordering::Relaxed),
+            allocation_count: COUNT.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Zero the total, count and peak. Outstanding bytes are kept so later frees still balance, the peak restarts
+    /// from them
+    pub fn reset(&self) {
+        TOTAL.store(0, Ordering::Relaxed);
+        COUNT.store(0, Ordering::Relaxed);
+        PEAK.store(OUTSTANDING.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
+}
+
+fn usable_size(ptr: *mut c_void) -> usize {
+    if ptr.is_null() {
+        0
+    } else {
+        unsafe { libc::malloc_usable_size(ptr) }
+    }
+}
+
+fn record_alloc(ptr: *mut c_void) {
+    let size = usable_size(ptr);
+    if ptr.is_null() {
+        return;
+    }
+    TOTAL.fetch_add(size, Ordering::Relaxed);
+    COUNT.fetch_add(1, Ordering::Relaxed);
+    let outstanding = OUTSTANDING.fetch_add(size, Ordering::Relaxed) + size;
+    PEAK.fetch_max(outstanding, Ordering::Relaxed);
+}
+
+// Memory allocated before tracking started can be freed afterwards, saturate rather than wrap
+fn record_free(size: usize) {
+    let _ = OUTSTANDING.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |o| Some(o.saturating_sub(size)));
+}
+
+extern "C" fn malloc(size: usize) -> *mut c_void {
+    let ptr = unsafe { libc::malloc(size) };
+    record_alloc(ptr);
+    ptr
+}
+
+extern "C" fn calloc(count: usize, size: usize) -> *mut c_void {
+    let ptr = unsafe { libc::calloc(count, size) };
+    record_alloc(ptr);
+    ptr
+}
+
+extern "C" fn realloc(ptr: *mut c_void, size: usize) -> *mut c_void {
+    let old_size = usable_size(ptr);
+    let new_ptr = unsafe { libc::realloc(ptr, size) };
+    if !new_ptr.is_null() {
+        record_free(old_size);
+        record_alloc(new_ptr);
+    } else if size == 0 {
+        // glibc frees the block and returns NULL, on failure the old block is left alone
+        record_free(old_size);
+    }
+    new_ptr
+}
+
+extern "C" fn free(ptr: *mut c_void) {
+    record_free(usable_size(ptr));
+    unsafe { libc::free(ptr) };
+}
+
+extern "C" fn posix_memalign(memptr: *mut *mut c_void, alignment: usize, size: usize) -> c_int {
+    let result = unsafe { libc::posix_memalign(memptr, alignment, size) };
+    if result == 0 {
+        record_alloc(unsafe { *memptr });
+    }
+    result
+}
+
+// Name and wrapper of every tracked function, for JNI::override_symbol
+pub(crate) fn wrappers() -> Vec<(&'static str, *const ())> {
+    vec![
+        ("malloc", malloc as *const ()),
+        ("calloc", calloc as *const ()),
+        ("realloc", realloc as *const ()),
+        ("free", free as *const ()),
+        ("posix_memalign", posix_memalign as *const ()),
+    ]
+}
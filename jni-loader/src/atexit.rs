@@ -0,0 +1,46 @@
+use std::{
+    ffi::{c_int, c_void},
+    sync::Mutex,
+};
+
+// Destructors registered by loaded libraries. C++ libraries register destructors for their globals with
+// __cxa_atexit, if those went to the host's libc they would run at host exit, after the library may have been unmapped
+struct Handler {
+    func: usize,
+    arg: usize,
+    dso_handle: usize,
+}
+
+static HANDLERS: Mutex<Vec<Handler>> = Mutex::new(Vec::new());
+
+pub(crate) extern "C" fn cxa_atexit(
+    func: extern "C" fn(*mut c_void), arg: *mut c_void, dso_handle: *mut c_void,
+) -> c_int {
+    HANDLERS.lock().unwrap().push(Handler { func: func as usize, arg: arg as usize, dso_handle: dso_handle as usize });
+    0
+}
+
+// Called by a library's own .fini code with its __dso_handle, or with null to run everything
+pub(crate) extern "C" fn cxa_finalize(dso_handle: *mut c_void) {
+    if dso_handle.is_null() {
+        run(0, usize::MAX);
+    } else {
+        run(dso_handle as usize, dso_handle as usize + 1);
+    }
+}
+
+// Run the destructors registered with a __dso_handle in [start, end), most recently registered first
+pub(crate) fn run(start: usize, end: usize) {
+    loop {
+        // The lock is released before calling out as a destructor may register more handlers
+        let handler = {
+            let mut handlers = HANDLERS.lock().unwrap();
+            let Some(index) = handlers.iter().rposition(|h| h.dso_handle >= start && h.dso_handle < end) else {
+                return;
+            };
+            handlers.remove(index)
+        };
+        let func: extern "C" fn(*mut c_void) = unsafe { std::mem::transmute(handler.func) };
+        func(handler.arg as *mut c_void);
+    }
+}
@@ -0,0 +1,35 @@
+use std::{
+    ops::Deref,
+    sync::{Arc, Mutex},
+};
+
+use super::JNI;
+
+/// A function pointer resolved from a library, which keeps the library mapped
+/// for as long as the pointer is reachable
+pub struct Callable<F: Copy> {
+    _jni: Arc<Mutex<Box<JNI>>>,
+    function: F,
+}
+
+impl<F: Copy> Callable<F> {
+    /// # Safety
+    /// `F` must be a function pointer type matching the symbol's real signature
+    pub(crate) unsafe fn new(jni: Arc<Mutex<Box<JNI>>>, address: *const ()) -> Self {
+        assert_eq!(std::mem::size_of::<F>(), std::mem::size_of::<*const ()>(), "F must be a function pointer");
+        let function = std::mem::transmute_copy::<*const (), F>(&address);
+        Self { _jni: jni, function }
+    }
+
+    pub fn get(&self) -> F {
+        self.function
+    }
+}
+
+impl<F: Copy> Deref for Callable<F> {
+    type Target = F;
+
+    fn deref(&self) -> &F {
+        &self.function
+    }
+}
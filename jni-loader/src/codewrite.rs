@@ -0,0 +1,204 @@
+use std::{
+    ffi::{c_int, c_void},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        OnceLock,
+    },
+};
+
+use nix::{
+    libc::{mprotect, siginfo_t, PROT_EXEC, PROT_READ, PROT_WRITE},
+    sys::signal::{raise, sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal},
+};
+
+use crate::mmap::system_page_size;
+
+// Everything here is read from a signal handler, so fixed size tables of atomics are used instead of anything that
+// could allocate or lock
+const MAX_RANGES: usize = 64;
+const MAX_EVENTS: usize = 1024;
+
+static RANGE_STARTS: [AtomicUsize; MAX_RANGES] = [const { AtomicUsize::new(0) }; MAX_RANGES];
+static RANGE_ENDS: [AtomicUsize; MAX_RANGES] = [const { AtomicUsize::new(0) }; MAX_RANGES];
+static EVENT_ADDRESSES: [AtomicUsize; MAX_EVENTS] = [const { AtomicUsize::new(0) }; MAX_EVENTS];
+static EVENT_INSTRUCTIONS: [AtomicUsize; MAX_EVENTS] = [const { AtomicUsize::new(0) }; MAX_EVENTS];
+static EVENT_COUNT: AtomicUsize = AtomicUsize::new(0);
+// Code mapped without execute permission, which is granted a page at a time as it is first run
+static DEMAND_STARTS: [AtomicUsize; MAX_RANGES] = [const { AtomicUsize::new(0) }; MAX_RANGES];
+static DEMAND_ENDS: [AtomicUsize; MAX_RANGES] = [const { AtomicUsize::new(0) }; MAX_RANGES];
+static DEMAND_PROTS: [AtomicUsize; MAX_RANGES] = [const { AtomicUsize::new(0) }; MAX_RANGES];
+static EXECUTED_PAGES: [AtomicUsize; MAX_EVENTS] = [const { AtomicUsize::new(0) }; MAX_EVENTS];
+static EXECUTED_COUNT: AtomicUsize = AtomicUsize::new(0);
+static PAGE_SIZE: AtomicUsize = AtomicUsize::new(0);
+static HANDLER_INSTALLED: OnceLock<Result<(), String>> = OnceLock::new(); // Kept so every caller sees a failure
+static PREVIOUS_ACTION: OnceLock<SigAction> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CodeWriteEvent {
+    pub address: usize,     // Address that was written to
+    pub instruction: usize, // Address of the instruction that did the write
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExecutedPage {
+    pub library: String, // Name the library was registered with
+    pub offset: usize,   // Offset of the page from the library's mapping base
+}
+
+/// Every page of code that has run so far in libraries loaded with MappingOptions::with_execute_on_demand, in the
+/// order they were first run
+pub fn executed_pages() -> Vec<ExecutedPage> {
+    executed_pages_in(0, usize::MAX)
+        .into_iter()
+        .filter_map(|page| {
+            let (base_address, library) = crate::debug::library_at(page as u64)?;
+            Some(ExecutedPage { library, offset: page - base_address as usize })
+        })
+        .collect()
+}
+
+// Start reporting writes to [start, end). The range must already be mapped without write permissions
+pub(crate) fn watch(start: usize, end: usize) -> Result<(), String> {
+    HANDLER_INSTALLED.get_or_init(install_handler).clone()?;
+    for (range_start, range_end) in RANGE_STARTS.iter().zip(RANGE_ENDS.iter()) {
+        if range_start.compare_exchange(0, start, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+            range_end.store(end, Ordering::SeqCst);
+            return Ok(());
+        }
+    }
+    Err("Too many watched code ranges".to_string())
+}
+
+pub(crate) fn unwatch(start: usize) {
+    for (range_start, range_end) in RANGE_STARTS.iter().zip(RANGE_ENDS.iter()) {
+        if range_start.load(Ordering::SeqCst) == start {
+            range_end.store(0, Ordering::SeqCst);
+            range_start.store(0, Ordering::SeqCst);
+        }
+    }
+}
+
+// Give pages in [start, end) the permissions `prot` as they are first executed. The range must already be mapped
+// without execute permission
+pub(crate) fn watch_execution(start: usize, end: usize, prot: c_int) -> Result<(), String> {
+    HANDLER_INSTALLED.get_or_init(install_handler).clone()?;
+    for ((range_start, range_end), range_prot) in DEMAND_STARTS.iter().zip(DEMAND_ENDS.iter()).zip(DEMAND_PROTS.iter())
+    {
+        if range_start.compare_exchange(0, start, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+            range_prot.store(prot as usize, Ordering::SeqCst);
+            range_end.store(end, Ordering::SeqCst);
+            return Ok(());
+        }
+    }
+    Err("Too many watched code ranges".to_string())
+}
+
+// Stop granting execute permission in the range starting at `start`, before its pages are unmapped and the address
+// can be reused
+pub(crate) fn unwatch_execution(start: usize) {
+    for ((range_start, range_end), range_prot) in DEMAND_STARTS.iter().zip(DEMAND_ENDS.iter()).zip(DEMAND_PROTS.iter())
+    {
+        if range_start.load(Ordering::SeqCst) == start {
+            range_end.store(0, Ordering::SeqCst);
+            range_prot.store(0, Ordering::SeqCst);
+            range_start.store(0, Ordering::SeqCst);
+        }
+    }
+}
+
+// Pages in [start, end) that have been executed, in the order they were first run
+pub(crate) fn executed_pages_in(start: usize, end: usize) -> Vec<usize> {
+    let count = std::cmp::min(EXECUTED_COUNT.load(Ordering::SeqCst), MAX_EVENTS);
+    (0..count).map(|i| EXECUTED_PAGES[i].load(Ordering::SeqCst)).filter(|&page| page >= start && page < end).collect()
+}
+
+// Events for writes that landed in [start, end)
+pub(crate) fn events_in(start: usize, end: usize) -> Vec<CodeWriteEvent> {
+    let count = std::cmp::min(EVENT_COUNT.load(Ordering::SeqCst), MAX_EVENTS);
+    (0..count)
+        .map(|i| CodeWriteEvent {
+            address: EVENT_ADDRESSES[i].load(Ordering::SeqCst),
+            instruction: EVENT_INSTRUCTIONS[i].load(Ordering::SeqCst),
+        })
+        .filter(|event| event.address >= start && event.address < end)
+        .collect()
+}
+
+fn install_handler() -> Result<(), String> {
+    PAGE_SIZE.store(system_page_size(), Ordering::SeqCst);
+    let action = SigAction::new(SigHandler::SigAction(segv_handler), SaFlags::SA_SIGINFO, SigSet::empty());
+    let previous_action = unsafe { sigaction(Signal::SIGSEGV, &action) }.map_err(|e| e.to_string())?;
+    let _ = PREVIOUS_ACTION.set(previous_action);
+    Ok(())
+}
+
+extern "C" fn segv_handler(signal: c_int, info: *mut siginfo_t, context: *mut c_void) {
+    let address = unsafe { (*info).si_addr() } as usize;
+    let page_size = PAGE_SIZE.load(Ordering::SeqCst);
+
+    // Instruction fetches fault with the address of the instruction, anything else in the range is a data access
+    // and is left to the write detection below
+    if address == instruction_pointer(context) {
+        let demand_prot = DEMAND_STARTS.iter().zip(DEMAND_ENDS.iter()).zip(DEMAND_PROTS.iter()).find_map(
+            |((range_start, range_end), range_prot)| {
+                let range_start = range_start.load(Ordering::SeqCst);
+                let in_range = range_start != 0 && address >= range_start && address < range_end.load(Ordering::SeqCst);
+                in_range.then(|| range_prot.load(Ordering::SeqCst) as c_int)
+            },
+        );
+        if let Some(demand_prot) = demand_prot {
+            let page = address & !(page_size - 1);
+            let index = EXECUTED_COUNT.fetch_add(1, Ordering::SeqCst);
+            if index < MAX_EVENTS {
+                EXECUTED_PAGES[index].store(page, Ordering::SeqCst);
+            }
+            unsafe { mprotect(page as *mut c_void, page_size, demand_prot) };
+            return;
+        }
+    }
+
+    let watched = RANGE_STARTS.iter().zip(RANGE_ENDS.iter()).any(|(range_start, range_end)| {
+        let range_start = range_start.load(Ordering::SeqCst);
+        range_start != 0 && address >= range_start && address < range_end.load(Ordering::SeqCst)
+    });
+    if !watched {
+        // Not ours, hand it on without uninstalling this handler so later writes are still caught
+        chain_previous_handler(signal, info, context);
+        return;
+    }
+
+    let index = EVENT_COUNT.fetch_add(1, Ordering::SeqCst);
+    if index < MAX_EVENTS {
+        EVENT_ADDRESSES[index].store(address, Ordering::SeqCst);
+        EVENT_INSTRUCTIONS[index].store(instruction_pointer(context), Ordering::SeqCst);
+    }
+    // Let the write go ahead, only the first write to each page is reported
+    let page = address & !(page_size - 1);
+    unsafe { mprotect(page as *mut c_void, page_size, PROT_READ | PROT_WRITE | PROT_EXEC) };
+}
+
+// Run whatever handled SIGSEGV before us. Faults nobody handles get the default action, like they would have without
+// this handler installed
+fn chain_previous_handler(signal: c_int, info: *mut siginfo_t, context: *mut c_void) {
+    match PREVIOUS_ACTION.get().map(|previous_action| previous_action.handler()) {
+        Some(SigHandler::SigAction(handler)) => handler(signal, info, context),
+        Some(SigHandler::Handler(handler)) => handler(signal),
+        // The kernel doesn't let a fault be ignored either, an ignored SIGSEGV from a fault is delivered as SIG_DFL
+        Some(SigHandler::SigDfl) | Some(SigHandler::SigIgn) | None => {
+            let action = SigAction::new(SigHandler::SigDfl, SaFlags::empty(), SigSet::empty());
+            let _ = unsafe { sigaction(Signal::SIGSEGV, &action) };
+            let _ = raise(Signal::SIGSEGV);
+        },
+    }
+}
+
+fn instruction_pointer(context: *mut c_void) -> usize {
+    let context = context as *mut nix::libc::ucontext_t;
+    #[cfg(target_arch = "x86_64")]
+    let instruction = unsafe { (*context).uc_mcontext.gregs[nix::libc::REG_RIP as usize] as usize };
+    #[cfg(target_arch = "aarch64")]
+    let instruction = unsafe { (*context).uc_mcontext.pc as usize };
+    #[cfg(all(not(target_arch = "x86_64"), not(target_arch = "aarch64")))]
+    let instruction = 0;
+    instruction
+}
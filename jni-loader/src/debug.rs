@@ -20,9 +20,35 @@ pub(crate) fn add_library(base_address: u64, name: &str) -> Result<(), NulError>
     Ok(())
 }
 
+// Called once relocations have been applied, add_library() only means the library has been mapped
+pub(crate) fn library_initialized(base_address: u64) {
+    let libraries = LIBRARIES.lock().unwrap();
+    let Some(name_ptr) = libraries.get(&base_address).map(|name| name.as_ptr()) else {
+        return;
+    };
+    drop(libraries);
+    jni_loader_lib_initialized(base_address, name_ptr);
+}
+
 pub(crate) fn remove_library(base_address: u64) {
     let mut libraries = LIBRARIES.lock().unwrap();
-    let _ = libraries.remove(&base_address);
+    let name = libraries.remove(&base_address);
+    drop(libraries);
+    // The name is kept alive until after the hook so it can be read
+    if let Some(name) = name {
+        jni_loader_lib_unloaded(base_address, name.as_ptr());
+    }
+}
+
+// The registered library with the highest base address at or below `address`, which is the one containing it as long
+// as the address is inside some library
+pub(crate) fn library_at(address: u64) -> Option<(u64, String)> {
+    let libraries = LIBRARIES.lock().unwrap();
+    libraries
+        .iter()
+        .filter(|(&base_address, _)| base_address <= address)
+        .max_by_key(|(&base_address, _)| base_address)
+        .map(|(&base_address, name)| (base_address, name.to_string_lossy().into_owned()))
 }
 
 #[repr(C)]
@@ -43,3 +69,9 @@ pub extern "C" fn jni_loader_iter_libs(callback: IterCallback) {
 
 #[no_mangle]
 pub extern "C" fn jni_loader_lib_loaded(_base_address: u64, _name: *const c_char) {}
+
+#[no_mangle]
+pub extern "C" fn jni_loader_lib_initialized(_base_address: u64, _name: *const c_char) {}
+
+#[no_mangle]
+pub extern "C" fn jni_loader_lib_unloaded(_base_address: u64, _name: *const c_char) {}
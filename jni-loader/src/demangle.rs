@@ -0,0 +1,33 @@
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SymbolName {
+    pub raw: String,               // The name symbols are looked up by
+    pub demangled: Option<String>, // Readable form of C++ names, requires the demangle feature
+}
+
+impl SymbolName {
+    pub(crate) fn new(raw: String) -> Self {
+        let demangled = demangle(&raw);
+        Self { raw, demangled }
+    }
+
+    /// The demangled name if there is one, otherwise the raw name
+    pub fn display_name(&self) -> &str {
+        self.demangled.as_deref().unwrap_or(&self.raw)
+    }
+}
+
+/// Demangle an Itanium C++ ABI name, e.g. `_ZN3foo3barEv` -> `foo::bar()`. Returns None for names that aren't mangled
+/// and always without the demangle feature
+pub fn demangle(name: &str) -> Option<String> {
+    #[cfg(feature = "demangle")]
+    let demangled = {
+        let symbol = cpp_demangle::Symbol::new(name).ok()?;
+        symbol.demangle(&cpp_demangle::DemangleOptions::default()).ok()
+    };
+    #[cfg(not(feature = "demangle"))]
+    let demangled = {
+        let _ = name;
+        None
+    };
+    demangled
+}
@@ -10,10 +10,29 @@ use std::{
 use nix::{
     libc::memcpy,
     sys::mman::{mmap, mprotect, munmap, MapFlags, ProtFlags},
-    unistd::{sysconf, SysconfVar},
 };
 
-use super::{Error, JNI, UNDEFINED_SYMBOL_VALUE};
+use super::{mmap::system_page_size, Error, JNI, UNDEFINED_SYMBOL_VALUE};
+
+/// A dlopen, dlsym or dlclose call made by a library, see JNI::record_dlfcn()
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DlfcnEvent {
+    Dlopen {
+        filename: String,
+        flags: i32,
+        handle: Option<usize>, // None when the library couldn't be opened
+    },
+    Dlsym {
+        handle: usize,
+        library: String, // Name of the library the handle refers to
+        symbol: String,
+        address: Option<usize>, // None when the symbol wasn't found
+    },
+    Dlclose {
+        handle: usize,
+        library: String,
+    },
+}
 
 pub struct DlopenSymbols {
     mapping_base: usize,
@@ -25,11 +44,7 @@ pub struct DlopenSymbols {
 
 impl DlopenSymbols {
     pub fn new(jni: *const JNI) -> Result<Self, Error> {
-        let page_size = sysconf(SysconfVar::PAGE_SIZE).map_err(|e| Error::MemoryMapFailed(e.to_string()))?;
-        let Some(page_size) = page_size else {
-            return Err(Error::MemoryMapFailed("Failed to get page size".to_string()));
-        };
-        let page_size = page_size as usize;
+        let page_size = system_page_size();
 
         let space_needed = 3 * asm::TRAMPOLINE_SIZE;
         let mapping_size = (space_needed + page_size - 1) & !(page_size - 1);
@@ -76,7 +91,7 @@ impl DlopenSymbols {
             unsafe { mprotect(mapping_base as *mut c_void, mapping_size, ProtFlags::PROT_READ | ProtFlags::PROT_EXEC) }
         {
             let _ = unsafe { munmap(mapping_base as *mut c_void, mapping_size) };
-            return Err(Error::MemoryMapFailed(errno.to_string()));
+            return Err(Error::MemoryProtectFailed(errno.to_string()));
         }
 
         Ok(Self {
@@ -6,8 +6,9 @@ pub unsafe extern "C" fn jni_dlfcn_trampoline() {
     asm!(
         ".quad 0x0102030405060708", // JNI*
         ".quad 0x0807060504030201", // Function pointer
-        "ldr x7, -16",              // Get JNI*
-        "ldr x8, -12",              // Get function pointer
+        "hint #34",                 // bti c, landing pad for indirect calls when BTI is enforced
+        "ldr x7, -20",              // Get JNI*
+        "ldr x8, -16",              // Get function pointer
         "mov x2, x1",               // Move args[1] to args[2]
         "mov x1, x0",               // Move args[0] to args[1]
         "mov x0, x7",               // Move JNI* into args[0]
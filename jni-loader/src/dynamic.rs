@@ -0,0 +1,221 @@
+use std::io::{Read, Seek};
+
+use elf::{
+    abi::{
+        DT_GNU_HASH, DT_HASH, DT_JMPREL, DT_PLTREL, DT_PLTRELSZ, DT_REL, DT_RELA, DT_RELASZ, DT_RELSZ, DT_STRSZ,
+        DT_STRTAB, DT_SYMENT, DT_SYMTAB, PT_LOAD,
+    },
+    endian::{AnyEndian, EndianParse},
+    file::Class,
+    relocation::{RelIterator, RelaIterator},
+    segment::ProgramHeader,
+    string_table::StringTable,
+    symbol::SymbolTable,
+    ElfStream,
+};
+
+use super::{Error, Relocation};
+
+// Copies of the dynamic symbol table, string table, hash tables and relocation tables located through .dynamic rather
+// than the section headers. Release builds are sometimes stripped of section headers entirely, in which case this is
+// the only way to find them
+pub(crate) struct DynamicTables {
+    endianness: AnyEndian,
+    class: Class,
+    symbols: Vec<u8>,
+    strings: Vec<u8>,
+    pub gnu_hash: Option<Vec<u8>>,
+    pub sysv_hash: Option<Vec<u8>>,
+    relocations: Vec<(&'static str, Vec<u8>)>, // Keyed by the section the table would normally be in, e.g. .rela.dyn
+}
+
+impl DynamicTables {
+    // Only reads the tables if the section headers don't describe .dynsym
+    pub fn read<S: Read + Seek>(elf_file: &mut ElfStream<AnyEndian, S>) -> Result<Option<Self>, Error> {
+        if elf_file.section_header_by_name(".dynsym").ok().flatten().is_some() {
+            return Ok(None);
+        }
+        let (endianness, class) = (elf_file.ehdr.endianness, elf_file.ehdr.class);
+        let Some(dynamic_section) = elf_file.dynamic()? else {
+            return Err(Error::NoDynamicSection);
+        };
+        let entries: Vec<(i64, u64)> = dynamic_section.iter().map(|entry| (entry.d_tag, entry.d_val())).collect();
+        let find = |tag: i64| entries.iter().find(|(d_tag, _)| *d_tag == tag).map(|&(_, value)| value as usize);
+        let (Some(strings_addr), Some(strings_size)) = (find(DT_STRTAB), find(DT_STRSZ)) else {
+            return Err(Error::NoDynstr);
+        };
+        let Some(symbols_addr) = find(DT_SYMTAB) else {
+            return Err(Error::MalformedElf("no DT_SYMTAB in .dynamic".to_string()));
+        };
+        let symbol_size = find(DT_SYMENT).filter(|&size| size != 0).unwrap_or(match class {
+            Class::ELF32 => 16,
+            Class::ELF64 => 24,
+        });
+
+        let strings = read_virtual(elf_file, strings_addr, strings_size).ok_or(Error::DynstrParseFailed)?;
+        let sysv_hash = find(DT_HASH).and_then(|addr| read_sysv_hash(elf_file, endianness, addr));
+        let gnu_hash = find(DT_GNU_HASH).and_then(|addr| read_to_segment_end(elf_file, addr));
+
+        // Nothing records how many symbols there are, so work it out from the hash tables
+        let symbol_count = if let Some(sysv_hash) = &sysv_hash {
+            endianness.parse_u32_at(&mut 4, sysv_hash).ok().map(|nchain| nchain as usize)
+        } else if let Some(gnu_hash) = &gnu_hash {
+            gnu_hash_symbol_count(endianness, class, gnu_hash)
+        } else if strings_addr > symbols_addr {
+            // The string table usually follows the symbol table
+            Some((strings_addr - symbols_addr) / symbol_size)
+        } else {
+            None
+        };
+        let symbol_count = symbol_count
+            .ok_or_else(|| Error::MalformedElf("can't tell how many dynamic symbols there are".to_string()))?;
+        let symbols = symbol_count
+            .checked_mul(symbol_size)
+            .and_then(|size| read_virtual(elf_file, symbols_addr, size))
+            .ok_or_else(|| Error::MalformedElf("dynamic symbol table is outside the file".to_string()))?;
+
+        // DT_PLTREL says whether DT_JMPREL holds REL or RELA entries
+        let jmprel = find(DT_JMPREL).zip(find(DT_PLTRELSZ));
+        let jmprel_name = if find(DT_PLTREL) == Some(DT_REL as usize) {
+            ".rel.plt"
+        } else {
+            ".rela.plt"
+        };
+        let mut relocations = Vec::new();
+        for (name, table) in
+            [(".rel.dyn", find(DT_REL).zip(find(DT_RELSZ))), (".rela.dyn", find(DT_RELA).zip(find(DT_RELASZ)))]
+        {
+            let Some((addr, mut size)) = table else {
+                continue;
+            };
+            // Some linkers also count the PLT relocations at the end of the other table, like ld.so apply them once
+            if let Some((jmprel_addr, jmprel_size)) = jmprel {
+                if jmprel_addr > addr && jmprel_addr.checked_add(jmprel_size) == addr.checked_add(size) {
+                    size = jmprel_addr - addr;
+                }
+            }
+            if let Some(data) = read_virtual(elf_file, addr, size) {
+                relocations.push((name, data));
+            }
+        }
+        if let Some(data) = jmprel.and_then(|(addr, size)| read_virtual(elf_file, addr, size)) {
+            relocations.push((jmprel_name, data));
+        }
+
+        Ok(Some(Self { endianness, class, symbols, strings, gnu_hash, sysv_hash, relocations }))
+    }
+
+    pub fn symbol_table(&self) -> (SymbolTable<'_, AnyEndian>, StringTable<'_>) {
+        (SymbolTable::new(self.endianness, self.class, &self.symbols), StringTable::new(&self.strings))
+    }
+
+    pub fn string_table(&self) -> StringTable<'_> {
+        StringTable::new(&self.strings)
+    }
+
+    // Relocations from the table that would normally be in the named section, e.g. .rela.plt for DT_JMPREL
+    pub fn relocations(&self, section_name: &str) -> Option<Vec<Relocation>> {
+        let (_, data) = self.relocations.iter().find(|(name, _)| *name == section_name)?;
+        let relocations = if section_name.starts_with(".rela") {
+            RelaIterator::new(self.endianness, self.class, data).map(Relocation::from).collect()
+        } else {
+            RelIterator::new(self.endianness, self.class, data).map(Relocation::from).collect()
+        };
+        Some(relocations)
+    }
+}
+
+// Use the section headers if they describe the dynamic symbol table, otherwise the tables found through .dynamic
+pub(crate) fn symbol_table<'a, S: Read + Seek>(
+    elf_file: &'a mut ElfStream<AnyEndian, S>, dynamic_tables: Option<&'a DynamicTables>,
+) -> Option<(SymbolTable<'a, AnyEndian>, StringTable<'a>)> {
+    if let Some(dynamic_tables) = dynamic_tables {
+        return Some(dynamic_tables.symbol_table());
+    }
+    elf_file.dynamic_symbol_table().ok()?
+}
+
+// Read the hash tables through DT_GNU_HASH and DT_HASH. For files whose section headers describe .dynsym, so
+// DynamicTables isn't used, but not the hash tables
+pub(crate) fn read_hash_tables<S: Read + Seek>(
+    elf_file: &mut ElfStream<AnyEndian, S>,
+) -> (Option<Vec<u8>>, Option<Vec<u8>>) {
+    let endianness = elf_file.ehdr.endianness;
+    let Ok(Some(dynamic_section)) = elf_file.dynamic() else {
+        return (None, None);
+    };
+    let entries: Vec<(i64, u64)> = dynamic_section.iter().map(|entry| (entry.d_tag, entry.d_val())).collect();
+    let find = |tag: i64| entries.iter().find(|(d_tag, _)| *d_tag == tag).map(|&(_, value)| value as usize);
+    let gnu_hash = find(DT_GNU_HASH).and_then(|addr| read_to_segment_end(elf_file, addr));
+    let sysv_hash = find(DT_HASH).and_then(|addr| read_sysv_hash(elf_file, endianness, addr));
+    (gnu_hash, sysv_hash)
+}
+
+// Copy data at a virtual address out of the file data of the loadable segment containing it. Addresses and sizes come
+// straight from the file, so anything that overflows is treated as not found
+fn read_virtual<S: Read + Seek>(elf_file: &mut ElfStream<AnyEndian, S>, addr: usize, size: usize) -> Option<Vec<u8>> {
+    let end = addr.checked_add(size)?;
+    let segment = *elf_file.segments().iter().find(|s| {
+        s.p_type == PT_LOAD
+            && s.p_vaddr as usize <= addr
+            && file_data_end(s).is_some_and(|segment_end| end <= segment_end)
+    })?;
+    let start = addr - segment.p_vaddr as usize;
+    let data = elf_file.segment_data(&segment).ok()?;
+    Some(data.get(start..start + size)?.to_vec())
+}
+
+fn read_to_segment_end<S: Read + Seek>(elf_file: &mut ElfStream<AnyEndian, S>, addr: usize) -> Option<Vec<u8>> {
+    let segment = *elf_file.segments().iter().find(|s| {
+        s.p_type == PT_LOAD
+            && s.p_vaddr as usize <= addr
+            && file_data_end(s).is_some_and(|segment_end| addr < segment_end)
+    })?;
+    let size = file_data_end(&segment)? - addr;
+    read_virtual(elf_file, addr, size)
+}
+
+// Virtual address just past the file data of a segment
+fn file_data_end(segment: &ProgramHeader) -> Option<usize> {
+    usize::try_from(segment.p_vaddr.checked_add(segment.p_filesz)?).ok()
+}
+
+// .hash is nbucket, nchain, then that many 32 bit bucket and chain entries
+fn read_sysv_hash<S: Read + Seek>(
+    elf_file: &mut ElfStream<AnyEndian, S>, endianness: AnyEndian, addr: usize,
+) -> Option<Vec<u8>> {
+    let header = read_virtual(elf_file, addr, 8)?;
+    let nbucket = endianness.parse_u32_at(&mut 0, &header).ok()? as usize;
+    let nchain = endianness.parse_u32_at(&mut 4, &header).ok()? as usize;
+    let size = nbucket.checked_add(nchain)?.checked_mul(4)?.checked_add(8)?;
+    read_virtual(elf_file, addr, size)
+}
+
+// The highest symbol index in .gnu.hash is at the end of the chain starting from the highest bucket
+fn gnu_hash_symbol_count(endianness: AnyEndian, class: Class, data: &[u8]) -> Option<usize> {
+    let nbuckets = endianness.parse_u32_at(&mut 0, data).ok()? as usize;
+    let symoffset = endianness.parse_u32_at(&mut 4, data).ok()? as usize;
+    let bloom_size = endianness.parse_u32_at(&mut 8, data).ok()? as usize;
+    let bloom_word_size = match class {
+        Class::ELF32 => 4,
+        Class::ELF64 => 8,
+    };
+    let buckets_offset = bloom_size.checked_mul(bloom_word_size)?.checked_add(16)?;
+    let chains_offset = nbuckets.checked_mul(4)?.checked_add(buckets_offset)?;
+    let mut last_symbol = 0;
+    for bucket in 0..nbuckets {
+        let symbol = endianness.parse_u32_at(&mut (buckets_offset + bucket * 4), data).ok()? as usize;
+        last_symbol = last_symbol.max(symbol);
+    }
+    if last_symbol < symoffset {
+        return Some(symoffset);
+    }
+    loop {
+        let mut chain_offset = (last_symbol - symoffset).checked_mul(4)?.checked_add(chains_offset)?;
+        let hash = endianness.parse_u32_at(&mut chain_offset, data).ok()?;
+        if hash & 1 == 1 {
+            return Some(last_symbol + 1);
+        }
+        last_symbol += 1;
+    }
+}
@@ -0,0 +1,57 @@
+use elf::{
+    endian::{AnyEndian, EndianParse},
+    file::Class,
+    ParseError,
+};
+
+// A copy of the .gnu.hash section along with its bloom filter. The chains are still walked by elf::hash::GnuHashTable,
+// this only exists so we can reject names the library doesn't define without parsing the table each time
+pub(crate) struct GnuHash {
+    data: Vec<u8>,
+    bloom_shift: u32,
+    bloom_word_bits: u32,
+    bloom: Vec<u64>,
+}
+
+impl GnuHash {
+    pub fn new(endianness: AnyEndian, class: Class, data: Vec<u8>) -> Result<Self, ParseError> {
+        // Header is nbuckets, symoffset, bloom_size and bloom_shift followed by the bloom words, which are the size of
+        // an address for the ELF class
+        let mut offset = 8;
+        let bloom_size = endianness.parse_u32_at(&mut offset, &data)?;
+        let bloom_shift = endianness.parse_u32_at(&mut offset, &data)?;
+        let mut bloom = Vec::with_capacity(bloom_size as usize);
+        for _ in 0..bloom_size {
+            let word = match class {
+                Class::ELF32 => endianness.parse_u32_at(&mut offset, &data)? as u64,
+                Class::ELF64 => endianness.parse_u64_at(&mut offset, &data)?,
+            };
+            bloom.push(word);
+        }
+        let bloom_word_bits = match class {
+            Class::ELF32 => 32,
+            Class::ELF64 => 64,
+        };
+        Ok(Self { data, bloom_shift, bloom_word_bits, bloom })
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    // False means the name is definitely not defined, true means it might be and the chain needs to be checked
+    pub fn may_contain(&self, name: &[u8]) -> bool {
+        if self.bloom.is_empty() {
+            return true;
+        }
+        let hash = gnu_hash(name);
+        let word = self.bloom[((hash / self.bloom_word_bits) as usize) % self.bloom.len()];
+        let mask = (1u64 << (hash % self.bloom_word_bits))
+            | (1u64 << (hash.wrapping_shr(self.bloom_shift) % self.bloom_word_bits));
+        word & mask == mask
+    }
+}
+
+fn gnu_hash(name: &[u8]) -> u32 {
+    name.iter().fold(5381u32, |hash, &c| hash.wrapping_mul(33).wrapping_add(c as u32))
+}
@@ -0,0 +1,52 @@
+use std::ffi::{c_void, CStr, CString};
+
+use nix::libc::{dlclose, dlerror, dlopen, dlsym, RTLD_DEFAULT, RTLD_LOCAL, RTLD_NOW};
+
+// A library loaded by the system loader, kept open until dropped
+pub(crate) struct HostLibrary {
+    handle: *mut c_void,
+}
+
+impl HostLibrary {
+    pub fn open(soname: &str) -> Result<Self, String> {
+        let soname = CString::new(soname).map_err(|e| e.to_string())?;
+        let handle = unsafe { dlopen(soname.as_ptr(), RTLD_NOW | RTLD_LOCAL) };
+        if handle.is_null() {
+            return Err(last_error());
+        }
+        Ok(Self { handle })
+    }
+
+    pub fn symbol(&self, symbol_name: &str) -> Option<usize> {
+        let symbol_name = CString::new(symbol_name).ok()?;
+        let address = unsafe { dlsym(self.handle, symbol_name.as_ptr()) };
+        if address.is_null() {
+            return None;
+        }
+        Some(address as usize)
+    }
+}
+
+// Look up a symbol in everything the host process has loaded
+pub(crate) fn default_symbol(symbol_name: &str) -> Option<usize> {
+    let symbol_name = CString::new(symbol_name).ok()?;
+    let address = unsafe { dlsym(RTLD_DEFAULT, symbol_name.as_ptr()) };
+    if address.is_null() {
+        return None;
+    }
+    Some(address as usize)
+}
+
+impl Drop for HostLibrary {
+    fn drop(&mut self) {
+        let _ = unsafe { dlclose(self.handle) };
+    }
+}
+
+fn last_error() -> String {
+    let error = unsafe { dlerror() };
+    if error.is_null() {
+        return "unknown error".to_string();
+    }
+    unsafe { CStr::from_ptr(error) }.to_string_lossy().into_owned()
+}
@@ -0,0 +1,205 @@
+// Calls functions whose signature is only known at runtime. Both supported calling conventions assign integer and
+// floating point arguments to registers independently, in order, so calling through a function pointer that takes
+// the maximum number of each puts every argument where the callee expects it. Extra registers are ignored by the
+// callee. Arguments that would be passed on the stack aren't supported
+
+/// Argument and return classes a signature can be described with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArgType {
+    Integer, // Any integer up to pointer size, including bool and char
+    Pointer,
+    F32,
+    F64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    Integer(usize),
+    Pointer(usize),
+    F32(f32),
+    F64(f64),
+    Void, // Only returned, for functions without a return value
+}
+
+impl Value {
+    fn arg_type(&self) -> Option<ArgType> {
+        match self {
+            Value::Integer(_) => Some(ArgType::Integer),
+            Value::Pointer(_) => Some(ArgType::Pointer),
+            Value::F32(_) => Some(ArgType::F32),
+            Value::F64(_) => Some(ArgType::F64),
+            Value::Void => None,
+        }
+    }
+}
+
+/// Describes a function's arguments and return value for JNI::invoke(), e.g.
+/// `CallSignature::new().arg(ArgType::Pointer).arg(ArgType::Integer).returns(ArgType::Integer)`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CallSignature {
+    args: Vec<ArgType>,
+    returns: Option<ArgType>, // None for void
+}
+
+impl CallSignature {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn arg(mut self, arg_type: ArgType) -> Self {
+        self.args.push(arg_type);
+        self
+    }
+
+    pub fn returns(mut self, return_type: ArgType) -> Self {
+        self.returns = Some(return_type);
+        self
+    }
+
+    pub fn args(&self) -> &[ArgType] {
+        &self.args
+    }
+
+    pub fn return_type(&self) -> Option<ArgType> {
+        self.returns
+    }
+}
+
+/// What JNI::invoke() calls, an exported symbol or a virtual address in the file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallTarget<'a> {
+    Symbol(&'a str),
+    Offset(usize),
+}
+
+impl<'a> From<&'a str> for CallTarget<'a> {
+    fn from(symbol_name: &'a str) -> Self {
+        CallTarget::Symbol(symbol_name)
+    }
+}
+
+impl From<usize> for CallTarget<'_> {
+    fn from(offset: usize) -> Self {
+        CallTarget::Offset(offset)
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+const INTEGER_REGISTERS: usize = 6; // rdi, rsi, rdx, rcx, r8, r9
+#[cfg(target_arch = "aarch64")]
+const INTEGER_REGISTERS: usize = 8; // x0-x7
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+const FLOAT_REGISTERS: usize = 8; // xmm0-xmm7 or v0-v7
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+type IntegerReturn = extern "C" fn(
+    usize,
+    usize,
+    usize,
+    usize,
+    usize,
+    usize,
+    usize,
+    usize,
+    f64,
+    f64,
+    f64,
+    f64,
+    f64,
+    f64,
+    f64,
+    f64,
+) -> usize;
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+type FloatReturn = extern "C" fn(
+    usize,
+    usize,
+    usize,
+    usize,
+    usize,
+    usize,
+    usize,
+    usize,
+    f64,
+    f64,
+    f64,
+    f64,
+    f64,
+    f64,
+    f64,
+    f64,
+) -> f64;
+
+// Check the arguments against the signature and call `address` with them
+//
+// # Safety
+// `address` must be a function matching `signature`
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+pub(crate) unsafe fn invoke(address: usize, signature: &CallSignature, args: &[Value]) -> Result<Value, String> {
+    if args.len() != signature.args.len() {
+        return Err(format!("Expected {} arguments, got {}", signature.args.len(), args.len()));
+    }
+    let mut integers = [0usize; 8];
+    let mut floats = [0f64; FLOAT_REGISTERS];
+    let (mut integer_count, mut float_count) = (0, 0);
+    for (index, (arg, arg_type)) in args.iter().zip(signature.args.iter()).enumerate() {
+        if arg.arg_type() != Some(*arg_type) {
+            return Err(format!("Argument {index} is {arg:?}, expected {arg_type:?}"));
+        }
+        match *arg {
+            Value::Integer(value) | Value::Pointer(value) => {
+                if integer_count == INTEGER_REGISTERS {
+                    return Err(format!("More than {INTEGER_REGISTERS} integer arguments aren't supported"));
+                }
+                integers[integer_count] = value;
+                integer_count += 1;
+            },
+            Value::F32(_) | Value::F64(_) => {
+                if float_count == FLOAT_REGISTERS {
+                    return Err(format!("More than {FLOAT_REGISTERS} floating point arguments aren't supported"));
+                }
+                // A float is read from the low 32 bits of the register, the rest is ignored
+                floats[float_count] = match *arg {
+                    Value::F32(value) => f64::from_bits(value.to_bits() as u64),
+                    Value::F64(value) => value,
+                    _ => unreachable!(),
+                };
+                float_count += 1;
+            },
+            Value::Void => unreachable!(),
+        }
+    }
+
+    let [i0, i1, i2, i3, i4, i5, i6, i7] = integers;
+    let [f0, f1, f2, f3, f4, f5, f6, f7] = floats;
+    let value = match signature.returns {
+        None => {
+            let function: IntegerReturn = std::mem::transmute(address);
+            function(i0, i1, i2, i3, i4, i5, i6, i7, f0, f1, f2, f3, f4, f5, f6, f7);
+            Value::Void
+        },
+        Some(ArgType::Integer) | Some(ArgType::Pointer) => {
+            let function: IntegerReturn = std::mem::transmute(address);
+            let value = function(i0, i1, i2, i3, i4, i5, i6, i7, f0, f1, f2, f3, f4, f5, f6, f7);
+            match signature.returns {
+                Some(ArgType::Pointer) => Value::Pointer(value),
+                _ => Value::Integer(value),
+            }
+        },
+        Some(ArgType::F32) => {
+            let function: FloatReturn = std::mem::transmute(address);
+            let value = function(i0, i1, i2, i3, i4, i5, i6, i7, f0, f1, f2, f3, f4, f5, f6, f7);
+            Value::F32(f32::from_bits(value.to_bits() as u32))
+        },
+        Some(ArgType::F64) => {
+            let function: FloatReturn = std::mem::transmute(address);
+            Value::F64(function(i0, i1, i2, i3, i4, i5, i6, i7, f0, f1, f2, f3, f4, f5, f6, f7))
+        },
+    };
+    Ok(value)
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+pub(crate) unsafe fn invoke(_address: usize, _signature: &CallSignature, _args: &[Value]) -> Result<Value, String> {
+    Err("Invoking functions isn't supported on this architecture".to_string())
+}
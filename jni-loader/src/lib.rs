@@ -2,95 +2,393 @@
 #![cfg_attr(feature = "inline-asm", feature(asm_const))]
 
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
+    env,
+    ffi::c_void,
     fmt::Debug,
     fs::{self, File},
-    path::PathBuf,
-    sync::{Arc, Mutex},
+    io::{Cursor, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
 };
 
 use elf::{
-    abi::{DT_NEEDED, DT_RUNPATH, ET_DYN, PT_LOAD},
+    abi::{
+        DF_1_ORIGIN, DF_ORIGIN, DT_FINI, DT_FINI_ARRAY, DT_FINI_ARRAYSZ, DT_FLAGS, DT_FLAGS_1, DT_INIT, DT_INIT_ARRAY,
+        DT_INIT_ARRAYSZ, DT_NEEDED, DT_RUNPATH, DT_SONAME, ET_DYN, PF_R, PF_W, PF_X, PT_GNU_STACK, PT_LOAD, PT_TLS,
+        SHN_UNDEF, STB_GLOBAL, STB_LOCAL, STB_WEAK, STT_NOTYPE, STT_TLS, STV_DEFAULT, STV_PROTECTED,
+    },
     endian::AnyEndian,
+    file::Class,
     hash::{GnuHashTable, SysVHashTable},
     relocation::{Rel, Rela},
     symbol::Symbol,
     ElfStream,
 };
-use log::{debug, error, info, trace};
+use log::{debug, error, info, trace, warn};
 use thiserror::Error;
 
+mod alloc;
+mod atexit;
+mod callable;
+mod codewrite;
 mod debug;
+mod demangle;
 #[cfg(feature = "inline-asm")]
 mod dlfcn;
+mod dynamic;
+mod hash;
+mod host;
+#[cfg(feature = "invoke")]
+mod invoke;
+pub mod libc_overrides;
 mod locate;
+mod mangle;
 mod mmap;
 #[cfg(feature = "inline-asm")]
 mod plt;
+mod property;
+mod reader;
+mod stack;
+mod syscalls;
+mod tls;
+mod validate;
 
-use mmap::MemoryMapping;
+pub use alloc::{AllocStats, AllocTracker};
+pub use callable::Callable;
+pub use codewrite::{executed_pages, CodeWriteEvent, ExecutedPage};
+pub use demangle::{demangle, SymbolName};
+#[cfg(feature = "inline-asm")]
+pub use dlfcn::DlfcnEvent;
+#[cfg(feature = "invoke")]
+pub use invoke::{ArgType, CallSignature, CallTarget, Value};
+pub use locate::SearchLocation;
+pub use mmap::{FileRange, MappingOptions, Provenance};
+use mmap::{MappingSource, MemoryMapping};
+#[cfg(feature = "inline-asm")]
+pub use plt::ImportTracer;
+use reader::{ReadSeek, SharedReader};
+pub use syscalls::{SyscallArgs, SyscallResult};
 
 pub struct JNI {
     path: PathBuf,
     name: String,
-    elf_file: ElfStream<AnyEndian, File>,
+    soname: Option<String>, // DT_SONAME, which the system loader uses to tell libraries apart
+    log_target: String,     // Target used for log messages, unique to each instance unless overridden
+    elf_file: ElfStream<AnyEndian, Box<dyn ReadSeek + Send>>,
+    class: Class,
+    machine: u16,
     pub mapping: MemoryMapping,
-    base_virtual_address: usize, // Lowest PT_LOAD virtual address
-    dependencies: HashMap<String, Option<Arc<Mutex<Box<JNI>>>>>,
+    base_virtual_address: usize,  // Lowest PT_LOAD virtual address
+    original_base: Option<usize>, // Where the library was loaded on the device addresses are being translated from
+    from_reader: bool,            // Loaded with from_reader, so path is only a name and not a file on disk
+    gnu_hash: Option<hash::GnuHash>,
+    sysv_hash: Option<Vec<u8>>, // From .hash, or DT_HASH when there is no section header for it
+    dynamic_tables: Option<dynamic::DynamicTables>, // Only set when the section headers don't describe .dynsym
+    dependencies: Vec<(String, Option<Arc<Mutex<Box<JNI>>>>)>, // In DT_NEEDED order once loaded
+    preloaded: Vec<Arc<Mutex<Box<JNI>>>>, // Searched before the dependencies, like LD_PRELOAD
     loaded_dependencies: bool,
     have_been_initialized: bool,
     symbol_overrides: HashMap<String, Option<usize>>,
+    versioned_overrides: HashMap<(String, String), Option<usize>>, // Keyed by name and version, checked first
+    weak_defaults: HashMap<String, usize>,                         // Used for weak imports nothing defines instead of 0
+    symbol_providers: HashMap<String, SymbolProvider>,
     looking_for_symbol: bool,
+    strict_executable_stack: bool,
+    strict_relocations: bool,
+    relocation_errors: Vec<RelocationError>,
+    strict_dependencies: bool,
+    dependency_errors: Vec<(String, Error)>, // Dependencies that were found but couldn't be loaded
+    dependency_observer: Option<Box<dyn FnMut(&DependencyEvent) + Send>>,
+    lazy_dependencies: bool,
+    deferred_dependencies: HashMap<String, (PathBuf, SearchLocation)>, // Located but not loaded until first searched
+    flat_namespace: Option<HashMap<String, (usize, u64)>>, // Address and size of every exported symbol in the graph
+    call_ifunc_resolvers: bool, // IRELATIVE relocations run code from the library, off for inert and no execute loads
+    call_constructors: bool,    // DT_INIT/DT_FINI and their arrays, off for inert and no execute loads
+    no_execute: bool,           // Mapped without execute permission, dependencies are loaded the same way
+    unloaded: bool,
+    host_fallback: bool,
+    tls_block: Option<tls::TlsBlock>,
+    java_vm: Option<usize>, // JavaVM* passed to JNI_OnLoad, JNI_OnUnload is called with it when dropped
     #[cfg(feature = "inline-asm")]
     plt_data: Option<plt::PltData>,
     #[cfg(feature = "inline-asm")]
+    plt_relocations: Option<Vec<Relocation>>, // Parsed on the first lazy resolution so later ones can index directly
+    #[cfg(feature = "inline-asm")]
     dlopen: Option<dlfcn::DlopenSymbols>,
     #[cfg(feature = "inline-asm")]
     dlopen_dependencies: HashMap<String, Option<Arc<Mutex<Box<JNI>>>>>,
+    #[cfg(feature = "inline-asm")]
+    plt_stubs: Vec<(usize, usize)>, // Each lazily bound slot and the PLT stub it starts out pointing at
+    #[cfg(feature = "inline-asm")]
+    import_trace: Option<Arc<Mutex<HashMap<String, usize>>>>, // Call counts while imports are being traced
+    #[cfg(feature = "inline-asm")]
+    dlfcn_trace: Option<Vec<dlfcn::DlfcnEvent>>, // Only recorded once record_dlfcn() is called
 }
 
 pub(crate) const UNDEFINED_SYMBOL_VALUE: usize = 0xBABECAFE;
+static NEXT_INSTANCE_ID: AtomicUsize = AtomicUsize::new(1);
 const STN_UNDEF: u64 = 0; // Undefined symbol
+#[cfg(not(target_arch = "x86_64"))]
+const R_NONE: u32 = 0; // Every architecture uses 0 for its no-op relocation
+
+// The relative relocation for architectures without a full backend. These only need the load base so can be applied
+// without knowing anything else about the architecture
+#[cfg(target_arch = "x86")]
+const GENERIC_RELATIVE: u32 = elf::abi::R_386_RELATIVE;
+#[cfg(target_arch = "arm")]
+const GENERIC_RELATIVE: u32 = elf::abi::R_ARM_RELATIVE;
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+const GENERIC_RELATIVE: u32 = elf::abi::R_RISCV_RELATIVE;
+#[cfg(not(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "x86",
+    target_arch = "arm",
+    target_arch = "riscv32",
+    target_arch = "riscv64"
+)))]
+const GENERIC_RELATIVE: u32 = u32::MAX;
 
 impl JNI {
     pub fn new(path: PathBuf) -> Result<Box<Self>, Error> {
+        Self::new_with_options(path, MappingOptions::default())
+    }
+
+    pub fn new_with_options(path: PathBuf, options: MappingOptions) -> Result<Box<Self>, Error> {
         if !path.exists() {
             return Err(Error::FileNotFound);
         }
+        let name = path.clone().file_name().unwrap().to_str().unwrap().to_owned();
+        if options.close_file() {
+            let Ok(data) = fs::read(&path) else {
+                return Err(Error::FailedToOpen);
+            };
+            let reader = SharedReader::new(Cursor::new(data));
+            let mut mapping_reader = reader.clone();
+            return Self::load(path, name, Box::new(reader), MappingSource::Reader(&mut mapping_reader), options);
+        }
         let Ok(file) = File::open(path.clone()) else {
             return Err(Error::FailedToOpen);
         };
         let Ok(mapping_file) = File::open(path.clone()) else {
             return Err(Error::FailedToOpen);
         };
-        let Ok(elf_file) = ElfStream::open_stream(file) else {
+        Self::load(path, name, Box::new(file), MappingSource::File(mapping_file), options)
+    }
+
+    /// Load a library for static analysis only. Nothing is mapped executable and IFUNC resolvers aren't called, so it
+    /// can be relocated and its data read but calling any of its functions is a mistake that will fault
+    pub fn new_readonly(path: PathBuf) -> Result<Box<Self>, Error> {
+        Self::new_with_options(path, MappingOptions::default().with_no_execute(true))
+    }
+
+    /// Like new but check the ELF headers are consistent with the file first, for libraries that can't be trusted. The
+    /// file is read into memory once and the same bytes are validated and mapped, so it can't be swapped in between
+    pub fn new_validated(path: PathBuf) -> Result<Box<Self>, Error> {
+        if !path.exists() {
+            return Err(Error::FileNotFound);
+        }
+        let name = path.clone().file_name().unwrap().to_str().unwrap().to_owned();
+        let Ok(mut file) = File::open(path.clone()) else {
+            return Err(Error::FailedToOpen);
+        };
+        let mut data = Vec::new();
+        file.read_to_end(&mut data).map_err(|_| Error::FailedToOpen)?;
+        let file_size = data.len() as u64;
+        let mut reader = SharedReader::new(Cursor::new(data));
+        let mut elf_file =
+            ElfStream::<AnyEndian, _>::open_stream(reader.clone()).map_err(|e| Error::MalformedElf(e.to_string()))?;
+        validate::validate(&mut elf_file, file_size).map_err(Error::MalformedElf)?;
+        reader.seek(SeekFrom::Start(0)).map_err(|_| Error::FailedToOpen)?;
+        let mut mapping_reader = reader.clone();
+        Self::load(path, name, Box::new(reader), MappingSource::Reader(&mut mapping_reader), MappingOptions::default())
+    }
+
+    /// Load, link and relocate a library then unload it again, reporting what was found. Nothing in the library or its
+    /// dependencies is ever executed, so this is safe to point at untrusted input, e.g. for fuzzing the loader
+    pub fn load_inert(path: PathBuf) -> Result<InertReport, Error> {
+        // Other than IFUNC resolvers, constructors and destructors initialize() and unload() only write relocations and
+        // the PLT, they never call into the library. Without execute permission none of those run, for dependencies
+        // too, and IRELATIVE relocations are reported as errors instead
+        let mut jni = Self::new_readonly(path)?;
+        jni.load_dependencies()?;
+        jni.initialize()?;
+        let dependencies = jni.dependencies.iter().map(|(name, lib)| (name.to_owned(), lib.is_some())).collect();
+        Ok(InertReport {
+            soname: jni.soname.clone(),
+            needed: jni.direct_needed(),
+            dependencies,
+            relocation_count: jni.relocations().len(),
+            relocation_errors: jni.relocation_errors.clone(),
+            imported_symbols: jni.imported_symbols(),
+            layout: jni.layout(),
+            tls: jni.tls_info(),
+        })
+    }
+
+    /// Call an exported symbol or a virtual address in the library with arguments described at runtime. The arguments
+    /// are checked against the signature before anything is called. Only arguments that are passed in registers are
+    /// supported, up to 6 integers on x86_64 or 8 on aarch64 and 8 floating point values
+    ///
+    /// # Safety
+    /// The function must really have the described signature
+    #[cfg(feature = "invoke")]
+    pub unsafe fn invoke<'a, T: Into<CallTarget<'a>>>(
+        &mut self, target: T, signature: &CallSignature, args: &[Value],
+    ) -> Result<Value, Error> {
+        let address = match target.into() {
+            CallTarget::Symbol(symbol_name) => match self.get_symbol(symbol_name) {
+                Some((address, _)) => address as usize,
+                None => return Err(Error::InvokeFailed(format!("Symbol {symbol_name} not found"))),
+            },
+            CallTarget::Offset(offset) => match self.checked_offset(offset) {
+                Some(address) => address,
+                None => return Err(Error::InvokeFailed(format!("Offset {offset:#x} is outside the library"))),
+            },
+        };
+        invoke::invoke(address, signature, args).map_err(Error::InvokeFailed)
+    }
+
+    /// Load a fresh copy of a library, call into it once and unload it again. For functions that keep state between
+    /// calls this gives every call a clean library. `setup` runs before dependencies are loaded so it can add
+    /// dependencies, symbol providers and overrides, `call` runs once everything is initialized
+    pub fn call_fresh<S: FnOnce(&mut JNI), C: FnOnce(&JNI) -> R, R>(
+        path: PathBuf, setup: S, call: C,
+    ) -> Result<R, Error> {
+        let mut jni = Self::new(path)?;
+        setup(&mut jni);
+        jni.load_dependencies()?;
+        jni.initialize()?;
+        let result = call(&jni);
+        jni.unload();
+        Ok(result)
+    }
+
+    /// Load a library from something other than a file, e.g. an in-memory decrypted copy. Segments are copied into
+    /// anonymous memory instead of being mapped from a file
+    pub fn from_reader<R: Read + Seek + Send + 'static>(name: &str, reader: R) -> Result<Box<Self>, Error> {
+        Self::from_reader_with_options(name, reader, MappingOptions::default())
+    }
+
+    pub fn from_reader_with_options<R: Read + Seek + Send + 'static>(
+        name: &str, reader: R, options: MappingOptions,
+    ) -> Result<Box<Self>, Error> {
+        let reader = SharedReader::new(reader);
+        let mut mapping_reader = reader.clone();
+        let mut jni = Self::load(
+            PathBuf::from(name),
+            name.to_owned(),
+            Box::new(reader),
+            MappingSource::Reader(&mut mapping_reader),
+            options,
+        )?;
+        jni.from_reader = true;
+        Ok(jni)
+    }
+
+    fn load(
+        path: PathBuf, name: String, reader: Box<dyn ReadSeek + Send>, mapping_source: MappingSource,
+        options: MappingOptions,
+    ) -> Result<Box<Self>, Error> {
+        let Ok(mut elf_file) = ElfStream::<AnyEndian, _>::open_stream(reader) else {
             return Err(Error::FailedToOpen);
         };
         if elf_file.ehdr.e_type != ET_DYN {
             return Err(Error::NotDynamicObject);
         }
-        let name = path.clone().file_name().unwrap().to_str().unwrap().to_owned();
-        info!(target: &name, "Trying to memory map {:?}", fs::canonicalize(path.clone()).unwrap_or(path.clone()));
-        let mapping = match MemoryMapping::new(mapping_file, elf_file.segments()) {
+        let (class, machine) = (elf_file.ehdr.class, elf_file.ehdr.e_machine);
+        let dynamic_tables = dynamic::DynamicTables::read(&mut elf_file)?;
+        let soname = read_dynamic_strings(&mut elf_file).ok().and_then(|strings| strings.soname);
+        let log_target = format!("{name}#{}", NEXT_INSTANCE_ID.fetch_add(1, Ordering::Relaxed));
+        info!(target: &log_target, "Trying to memory map {:?}", fs::canonicalize(path.clone()).unwrap_or(path.clone()));
+        // Program headers are normally sorted by address but nothing requires it, so use the lowest rather than the first
+        let load_segments = elf_file.segments().iter().filter(|s| s.p_type == PT_LOAD);
+        let Some(base_virtual_address) = load_segments.map(|s| s.p_vaddr as usize).min() else {
+            return Err(Error::MalformedElf("no PT_LOAD segments".to_string()));
+        };
+        let options = options.with_bti(property::requests_bti(&mut elf_file));
+        let page_size = mmap::system_page_size();
+        let mapping = match MemoryMapping::with_page_size(mapping_source, elf_file.segments(), &options, page_size) {
             Ok(mapping) => mapping,
             Err(error) => return Err(Error::MemoryMapFailed(error)),
         };
-        let base_virtual_address = elf_file.segments().iter().find(|&s| s.p_type == PT_LOAD).unwrap().p_vaddr as usize;
         debug::add_library(mapping.base as u64, &name).map_err(Error::DebugEntry)?;
+        // Stripped files can keep section headers for .dynsym but not the hash tables, .dynamic still points at them
+        let (dynamic_gnu_hash, dynamic_sysv_hash) = match dynamic_tables.as_ref() {
+            Some(dynamic_tables) => (dynamic_tables.gnu_hash.clone(), dynamic_tables.sysv_hash.clone()),
+            None => dynamic::read_hash_tables(&mut elf_file),
+        };
+        let gnu_hash = match elf_file.section_header_by_name(".gnu.hash") {
+            Ok(Some(&gnu_hash_section_header)) => {
+                let (endianness, class) = (elf_file.ehdr.endianness, elf_file.ehdr.class);
+                // Only .debug sections can be compressed
+                let gnu_hash_section = elf_file.section_data(&gnu_hash_section_header).ok().map(|(d, _)| d.to_vec());
+                gnu_hash_section.and_then(|data| hash::GnuHash::new(endianness, class, data).ok())
+            },
+            _ => {
+                let (endianness, class) = (elf_file.ehdr.endianness, elf_file.ehdr.class);
+                dynamic_gnu_hash.and_then(|data| hash::GnuHash::new(endianness, class, data).ok())
+            },
+        };
+        let sysv_hash = match elf_file.section_header_by_name(".hash") {
+            Ok(Some(&sysv_hash_section_header)) => {
+                elf_file.section_data(&sysv_hash_section_header).ok().map(|(d, _)| d.to_vec())
+            },
+            _ => dynamic_sysv_hash,
+        };
+
+        // Destructors registered by the library are run when it is dropped instead of at host exit
+        let symbol_overrides = HashMap::from([
+            ("__cxa_atexit".to_owned(), Some(atexit::cxa_atexit as usize)),
+            ("__cxa_finalize".to_owned(), Some(atexit::cxa_finalize as usize)),
+        ]);
 
         #[cfg(not(feature = "inline-asm"))]
         {
             Ok(Box::new(Self {
                 path,
                 name,
+                soname,
+                log_target,
                 elf_file,
+                class,
+                machine,
                 mapping,
                 base_virtual_address,
-                dependencies: HashMap::new(),
+                original_base: None,
+                from_reader: false,
+                gnu_hash,
+                sysv_hash,
+                dynamic_tables,
+                dependencies: Vec::new(),
+                preloaded: Vec::new(),
                 loaded_dependencies: false,
                 have_been_initialized: false,
-                symbol_overrides: HashMap::new(),
+                symbol_overrides,
+                versioned_overrides: HashMap::new(),
+                weak_defaults: HashMap::new(),
+                symbol_providers: HashMap::new(),
                 looking_for_symbol: false,
+                strict_executable_stack: false,
+                strict_relocations: false,
+                relocation_errors: Vec::new(),
+                strict_dependencies: false,
+                dependency_errors: Vec::new(),
+                dependency_observer: None,
+                lazy_dependencies: false,
+                deferred_dependencies: HashMap::new(),
+                flat_namespace: None,
+                call_ifunc_resolvers: !options.no_execute(),
+                call_constructors: !options.no_execute(),
+                no_execute: options.no_execute(),
+                unloaded: false,
+                host_fallback: false,
+                tls_block: None,
+                java_vm: None,
             }))
         }
         #[cfg(feature = "inline-asm")]
@@ -98,17 +396,50 @@ impl JNI {
             let mut jni = Box::new(Self {
                 path,
                 name,
+                soname,
+                log_target,
                 elf_file,
+                class,
+                machine,
                 mapping,
                 base_virtual_address,
-                dependencies: HashMap::new(),
+                original_base: None,
+                from_reader: false,
+                gnu_hash,
+                sysv_hash,
+                dynamic_tables,
+                dependencies: Vec::new(),
+                preloaded: Vec::new(),
                 loaded_dependencies: false,
                 have_been_initialized: false,
-                symbol_overrides: HashMap::new(),
+                symbol_overrides,
+                versioned_overrides: HashMap::new(),
+                weak_defaults: HashMap::new(),
+                symbol_providers: HashMap::new(),
                 looking_for_symbol: false,
+                strict_executable_stack: false,
+                strict_relocations: false,
+                relocation_errors: Vec::new(),
+                strict_dependencies: false,
+                dependency_errors: Vec::new(),
+                dependency_observer: None,
+                lazy_dependencies: false,
+                deferred_dependencies: HashMap::new(),
+                flat_namespace: None,
+                call_ifunc_resolvers: !options.no_execute(),
+                call_constructors: !options.no_execute(),
+                no_execute: options.no_execute(),
+                unloaded: false,
+                host_fallback: false,
+                tls_block: None,
+                java_vm: None,
                 plt_data: None,
+                plt_relocations: None,
                 dlopen: None,
                 dlopen_dependencies: HashMap::new(),
+                plt_stubs: Vec::new(),
+                import_trace: None,
+                dlfcn_trace: None,
             });
             let jni_addr = &mut *jni as *mut JNI;
             jni.plt_data = Some(plt::PltData::new(jni_addr));
@@ -123,12 +454,69 @@ impl JNI {
         }
     }
 
+    /// Load and fully initialize a library so one instance can be handed to several parents with
+    /// add_shared_dependency, e.g. both sides of a diamond dependency. Parents initializing it again is a no-op
+    pub fn load_shared(path: PathBuf) -> Result<Arc<Mutex<Box<Self>>>, Error> {
+        let mut jni = Self::new(path)?;
+        jni.load_dependencies()?;
+        jni.initialize()?;
+        Ok(Arc::new(Mutex::new(jni)))
+    }
+
+    /// The library's DT_SONAME, if it has one. This can differ from the file name
+    pub fn soname(&self) -> Option<&str> {
+        self.soname.as_deref()
+    }
+
+    /// Replace the log target used by this instance, by default this is the file name followed by a unique id
+    pub fn set_label(&mut self, label: &str) {
+        self.log_target = label.to_owned();
+    }
+
     pub fn add_dependency(&mut self, name: &str, lib: Option<Box<JNI>>) {
-        self.dependencies.insert(name.to_string(), lib.map(Mutex::new).map(Arc::new));
+        self.insert_dependency(name.to_string(), lib.map(Mutex::new).map(Arc::new));
     }
 
     pub fn add_shared_dependency(&mut self, name: &str, lib: Option<Arc<Mutex<Box<JNI>>>>) {
-        self.dependencies.insert(name.to_string(), lib);
+        self.insert_dependency(name.to_string(), lib);
+    }
+
+    fn insert_dependency(&mut self, name: String, lib: Option<Arc<Mutex<Box<JNI>>>>) {
+        match self.dependencies.iter_mut().find(|(dependency_name, dependency)| {
+            dependency_matches(&name, dependency_name, dependency)
+                || lib.as_ref().and_then(|l| l.try_lock().ok()?.soname.clone()).is_some_and(|s| s == *dependency_name)
+        }) {
+            Some((_, dependency)) => *dependency = lib,
+            None => self.dependencies.push((name, lib)),
+        }
+    }
+
+    /// Interpose a library's exported symbols over everything in the dependency graph, like LD_PRELOAD. Libraries
+    /// preloaded first take priority
+    pub fn preload(&mut self, lib: Box<JNI>) {
+        self.preloaded.push(Arc::new(Mutex::new(lib)));
+    }
+
+    /// Satisfy a dependency using a table of symbol addresses instead of mapping the library, e.g. to point libc
+    /// functions at the host's copy
+    pub fn add_symbol_provider(&mut self, name: &str, symbols: HashMap<String, *const ()>) {
+        let symbols = symbols.into_iter().map(|(symbol_name, address)| (symbol_name, address as usize)).collect();
+        self.symbol_providers.insert(name.to_string(), SymbolProvider::Table(symbols));
+    }
+
+    /// Resolve symbols that can't be found in any dependency using the symbols already loaded in the host process.
+    /// This can hide a missing dependency so it's off by default
+    pub fn set_host_fallback(&mut self, host_fallback: bool) {
+        self.host_fallback = host_fallback;
+    }
+
+    /// Satisfy a dependency using the real library loaded by the system loader. The library stays loaded for as long
+    /// as this instance exists
+    pub fn link_host_library(&mut self, soname: &str) -> Result<(), Error> {
+        let host_library = host::HostLibrary::open(soname).map_err(Error::HostLibrary)?;
+        debug!(target: &self.log_target, "Linked host library {soname}");
+        self.symbol_providers.insert(soname.to_string(), SymbolProvider::Host(host_library));
+        Ok(())
     }
 
     pub fn load_dependencies(&mut self) -> Result<(), Error> {
@@ -137,68 +525,568 @@ impl JNI {
         }
         self.loaded_dependencies = true;
 
-        // Dependencies are stored using DT_NEEDED keys in the .dynamic section. We also need DT_RUNPATH for locating
-        let Ok(Some(dynamic_section)) = self.elf_file.dynamic() else {
-            return Err(Error::NoDyanmicSection);
+        let DynamicStrings { needed: dependencies, runpath: dt_runpath, origin_required, .. } =
+            read_dynamic_strings(&mut self.elf_file)?;
+
+        // Loop through dependencies, if they haven't been overridden then try to locate and load them
+        let parent_dir = if origin_required && self.from_reader {
+            // There is no file to take the directory from, only the name the reader was given
+            warn!(target: &self.log_target, "DF_ORIGIN is set but the library was loaded from a reader, $ORIGIN won't be expanded");
+            None
+        } else if origin_required {
+            // The library relies on $ORIGIN, so it has to come from where the file really is rather than the path we
+            // were given, which could be relative or a symlink
+            match fs::canonicalize(&self.path).ok().and_then(|p| p.parent().map(PathBuf::from)) {
+                Some(origin) => {
+                    debug!(target: &self.log_target, "DF_ORIGIN is set, using origin {origin:?}");
+                    Some(origin)
+                },
+                // Only a problem if a search path actually needs it expanded
+                None if dt_runpath.as_ref().and_then(|p| p.to_str()).is_some_and(has_origin_token)
+                    || env::var("LD_LIBRARY_PATH").is_ok_and(|p| has_origin_token(&p)) =>
+                {
+                    error!(target: &self.log_target, "DF_ORIGIN is set but the library's directory can't be determined from {:?}", self.path);
+                    return Err(Error::OriginUnavailable);
+                },
+                None => {
+                    warn!(target: &self.log_target, "DF_ORIGIN is set but the library's directory can't be determined from {:?}", self.path);
+                    self.path.parent().map(PathBuf::from)
+                },
+            }
+        } else {
+            self.path.parent().map(PathBuf::from)
         };
-        let mut dependency_offsets = Vec::new();
-        let mut dt_runpath_offset = None;
-        for entry in dynamic_section {
-            match entry.d_tag {
-                DT_NEEDED => {
-                    dependency_offsets.push(entry.d_val() as usize);
+        // Dependencies of a library that can't run any code can't run any either
+        let options = MappingOptions::default().with_no_execute(self.no_execute);
+        for lib_name in dependencies.iter().cloned() {
+            trace!(target: &self.log_target, "Looking for dependency {lib_name}");
+            if let Some((_, dependency)) =
+                self.dependencies.iter().find(|(name, dependency)| dependency_matches(&lib_name, name, dependency))
+            {
+                let dependency_path = dependency.as_ref().map(|d| d.lock().unwrap().path.to_owned());
+                debug!(target: &self.log_target, "Found dependency {lib_name} - {dependency_path:?}");
+                let loaded = dependency_path.is_some();
+                self.report_dependency(lib_name, DependencySource::Added, dependency_path, loaded);
+                continue;
+            }
+            if self.symbol_providers.contains_key(&lib_name) {
+                debug!(target: &self.log_target, "Found dependency {lib_name} - Symbol provider");
+                self.report_dependency(lib_name, DependencySource::SymbolProvider, None, true);
+                continue;
+            }
+            match locate::locate_library_with_location(&lib_name, None, parent_dir.clone(), dt_runpath.clone()) {
+                Some((lib_path, location)) if self.lazy_dependencies => {
+                    debug!(target: &self.log_target, "Found dependency {lib_name} - {lib_path:?} ({location:?}), deferring loading it");
+                    self.deferred_dependencies.insert(lib_name.clone(), (lib_path, location));
+                    self.insert_dependency(lib_name, None);
+                },
+                Some((lib_path, location)) => match JNI::new_with_options(lib_path.clone(), options.clone()) {
+                    Ok(dependency) => {
+                        debug!(target: &self.log_target, "Found dependency {lib_name} - {:?} ({location:?})", dependency.path);
+                        let source = DependencySource::Search(location);
+                        self.report_dependency(lib_name.clone(), source, Some(lib_path), true);
+                        self.insert_dependency(lib_name, Some(Arc::new(Mutex::new(dependency))));
+                    },
+                    Err(error) => {
+                        error!(target: &self.log_target, "Found dependency {lib_name} at {lib_path:?} but failed to load it - {error}");
+                        let source = DependencySource::Search(location);
+                        self.report_dependency(lib_name.clone(), source, Some(lib_path), false);
+                        if self.strict_dependencies {
+                            return Err(error);
+                        }
+                        self.dependency_errors.push((lib_name.clone(), error));
+                        self.insert_dependency(lib_name, None);
+                    },
                 },
-                DT_RUNPATH => {
-                    dt_runpath_offset = Some(entry.d_val() as usize);
+                None => {
+                    debug!(target: &self.log_target, "Found dependency {lib_name} - None");
+                    self.report_dependency(lib_name.clone(), DependencySource::NotFound, None, false);
+                    self.insert_dependency(lib_name, None);
                 },
-                _ => {},
             }
         }
 
-        // Values from .dynamic are offsets into the .dynstr string table
-        let Ok(Some(dynamic_string_table_header)) = self.elf_file.section_header_by_name(".dynstr") else {
-            return Err(Error::NoDyanmicSection);
+        // Symbols are searched for in DT_NEEDED order. Dependencies that were added but aren't needed go last
+        self.dependencies.sort_by_key(|(name, _)| dependencies.iter().position(|n| n == name).unwrap_or(usize::MAX));
+
+        // A directory walk could have found a library with the right name built for a different architecture, and
+        // manually added dependencies aren't checked either
+        for (lib_name, dependency) in self.dependencies.iter() {
+            if let Some(dependency) = dependency {
+                let dependency = dependency.lock().unwrap();
+                if dependency.class != self.class || dependency.machine != self.machine {
+                    error!(target: &self.log_target, "Dependency {lib_name} is {:?} machine {}, expected {:?} machine {}", dependency.class, dependency.machine, self.class, self.machine);
+                    return Err(Error::ArchitectureMismatch(lib_name.to_owned()));
+                }
+            }
+        }
+
+        let loaded_dependencies: Vec<_> =
+            self.dependencies.iter().filter_map(|(name, lib)| Some((name.clone(), lib.clone()?))).collect();
+        for (lib_name, dependency) in loaded_dependencies {
+            self.check_dependency_versions(&lib_name, &mut dependency.lock().unwrap())?;
+        }
+        Ok(())
+    }
+
+    // Make sure a dependency defines every version .gnu.version_r says this library needs from it, otherwise symbols
+    // would bind to an older version with a different ABI. Dependencies without version definitions aren't checked
+    fn check_dependency_versions(&mut self, lib_name: &str, dependency: &mut JNI) -> Result<(), Error> {
+        let Some(defined_versions) = dependency.defined_versions() else {
+            return Ok(());
         };
-        let dynamic_string_table_header = *dynamic_string_table_header; // End mutable borrow of self.elf_file
-        let Ok(dynamic_string_table) = self.elf_file.section_data_as_strtab(&dynamic_string_table_header) else {
-            return Err(Error::NoDyanmicSection);
+        let required_versions = self.required_versions();
+        let missing_versions = required_versions.iter().filter(|(file, version)| {
+            (file == lib_name || dependency.soname() == Some(file.as_str())) && !defined_versions.contains(version)
+        });
+        for (_, version) in missing_versions {
+            error!(target: &self.log_target, "Dependency {lib_name} doesn't provide required version {version}");
+            let error = Error::MissingSymbolVersion { dependency: lib_name.to_owned(), version: version.to_owned() };
+            if self.strict_dependencies {
+                return Err(error);
+            }
+            self.dependency_errors.push((lib_name.to_owned(), error));
+        }
+        Ok(())
+    }
+
+    // Every (file, version) pair from .gnu.version_r that an imported symbol uses
+    fn required_versions(&mut self) -> HashSet<(String, String)> {
+        let symbol_count = dynamic::symbol_table(&mut self.elf_file, self.dynamic_tables.as_ref())
+            .map_or(0, |(symbol_table, _)| symbol_table.len());
+        let Ok(Some(version_table)) = self.elf_file.symbol_version_table() else {
+            return HashSet::new();
         };
-        let dependencies: Vec<String> = dependency_offsets
-            .into_iter()
-            .flat_map(|offset| dynamic_string_table.get(offset))
-            .map(|s| s.to_string())
+        (0..symbol_count)
+            .filter_map(|index| version_table.get_requirement(index).ok()?)
+            .map(|requirement| (requirement.file.to_owned(), requirement.name.to_owned()))
+            .collect()
+    }
+
+    // Names of the versions in .gnu.version_d that exported symbols use, None without version definitions
+    fn defined_versions(&mut self) -> Option<HashSet<String>> {
+        let symbol_count = dynamic::symbol_table(&mut self.elf_file, self.dynamic_tables.as_ref())
+            .map_or(0, |(symbol_table, _)| symbol_table.len());
+        let version_table = self.elf_file.symbol_version_table().ok()??;
+        let definitions: HashSet<String> = (0..symbol_count)
+            .filter_map(|index| version_table.get_definition(index).ok()?)
+            .filter_map(|mut definition| definition.names.next()?.ok().map(str::to_owned))
             .collect();
+        (!definitions.is_empty()).then_some(definitions)
+    }
 
-        // Loop through dependencies, if they haven't been overridden then try to locate and load them
-        let parent_dir = self.path.parent().map(PathBuf::from);
-        let dt_runpath = dt_runpath_offset.and_then(|offset| dynamic_string_table.get(offset).ok()).map(PathBuf::from);
-        for lib_name in dependencies {
-            trace!(target: &self.name, "Looking for dependency {lib_name}");
-            if let Some(dependency) = self.dependencies.get(&lib_name) {
-                debug!(target: &self.name, "Found dependency {lib_name} - {:?}", dependency.as_ref().map(|d| d.lock().unwrap().path.to_owned()));
+    /// Only locate dependencies in load_dependencies(), each one is loaded the first time a symbol lookup has to search
+    /// it and initialized once a symbol resolves into it. Dependencies that are never searched, e.g. because every
+    /// symbol they would provide is overridden, are never mapped. Must be set before load_dependencies()
+    pub fn set_lazy_dependencies(&mut self, lazy: bool) {
+        self.lazy_dependencies = lazy;
+    }
+
+    /// Dependencies that have been located but not loaded yet because lazy dependencies are enabled
+    pub fn deferred_dependencies(&self) -> Vec<&str> {
+        self.deferred_dependencies.keys().map(String::as_str).collect()
+    }
+
+    // Load a dependency load_dependencies() deferred. Failures are recorded in dependency_errors as the lookup that
+    // triggered this can't return them
+    fn load_deferred_dependency(&mut self, index: usize) {
+        let lib_name = self.dependencies[index].0.clone();
+        let Some((lib_path, location)) = self.deferred_dependencies.remove(&lib_name) else {
+            return;
+        };
+        let source = DependencySource::Search(location);
+        let options = MappingOptions::default().with_no_execute(self.no_execute);
+        match JNI::new_with_options(lib_path.clone(), options) {
+            Ok(dependency) if dependency.class != self.class || dependency.machine != self.machine => {
+                error!(target: &self.log_target, "Dependency {lib_name} is {:?} machine {}, expected {:?} machine {}", dependency.class, dependency.machine, self.class, self.machine);
+                self.report_dependency(lib_name.clone(), source, Some(lib_path), false);
+                self.dependency_errors.push((lib_name.clone(), Error::ArchitectureMismatch(lib_name)));
+            },
+            Ok(mut dependency) => {
+                debug!(target: &self.log_target, "Loaded deferred dependency {lib_name} - {:?}", dependency.path);
+                // Nothing can return the error from here, so it is recorded even in strict mode
+                if let Err(error) = self.check_dependency_versions(&lib_name, &mut dependency) {
+                    self.dependency_errors.push((lib_name.clone(), error));
+                }
+                self.report_dependency(lib_name, source, Some(lib_path), true);
+                self.dependencies[index].1 = Some(Arc::new(Mutex::new(dependency)));
+            },
+            Err(error) => {
+                error!(target: &self.log_target, "Found dependency {lib_name} at {lib_path:?} but failed to load it - {error}");
+                self.report_dependency(lib_name.clone(), source, Some(lib_path), false);
+                self.dependency_errors.push((lib_name, error));
+            },
+        }
+    }
+
+    /// Call `observer` for each DT_NEEDED entry as load_dependencies() decides where it comes from
+    pub fn observe_dependencies<F: FnMut(&DependencyEvent) + Send + 'static>(&mut self, observer: F) {
+        self.dependency_observer = Some(Box::new(observer));
+    }
+
+    fn report_dependency(&mut self, name: String, source: DependencySource, path: Option<PathBuf>, loaded: bool) {
+        if let Some(observer) = self.dependency_observer.as_mut() {
+            observer(&DependencyEvent { name, source, path, loaded });
+        }
+    }
+
+    /// List the libraries this library directly depends on (DT_NEEDED) without loading them
+    pub fn direct_needed(&mut self) -> Vec<String> {
+        read_dynamic_strings(&mut self.elf_file).map(|strings| strings.needed).unwrap_or_default()
+    }
+
+    /// List every library needed by a library, including the dependencies of its dependencies, without mapping
+    /// anything. Libraries that can't be located are included but their own dependencies can't be listed
+    pub fn needed_libraries(path: &Path) -> Result<Vec<String>, Error> {
+        let mut needed: Vec<String> = Vec::new();
+        let Ok(file) = File::open(path) else {
+            return Err(Error::FailedToOpen);
+        };
+        let Ok(elf_file) = ElfStream::<AnyEndian, _>::open_stream(file) else {
+            return Err(Error::FailedToOpen);
+        };
+        let mut pending = vec![(path.to_path_buf(), elf_file)];
+        while let Some((lib_path, mut elf_file)) = pending.pop() {
+            // Only the library we were asked about has to be valid, anything else is just skipped
+            let Ok(DynamicStrings { needed: dependencies, runpath: dt_runpath, .. }) =
+                read_dynamic_strings(&mut elf_file)
+            else {
+                if lib_path == path {
+                    return Err(Error::NoDynamicSection);
+                }
+                continue;
+            };
+            let parent_dir = lib_path.parent().map(PathBuf::from);
+            for lib_name in dependencies {
+                if needed.contains(&lib_name) {
+                    continue;
+                }
+                let dependency_path =
+                    locate::locate_library_internal(&lib_name, None, parent_dir.clone(), dt_runpath.clone());
+                if let Some(dependency_path) = dependency_path {
+                    if let Ok(dependency_file) = File::open(&dependency_path) {
+                        if let Ok(dependency_elf_file) = ElfStream::<AnyEndian, _>::open_stream(dependency_file) {
+                            pending.push((dependency_path, dependency_elf_file));
+                        }
+                    }
+                }
+                needed.push(lib_name);
+            }
+        }
+        Ok(needed)
+    }
+
+    /// Same as imported_symbols, along with demangled C++ names when the demangle feature is enabled
+    pub fn imported_symbol_names(&mut self) -> Vec<SymbolName> {
+        self.imported_symbols().into_iter().map(SymbolName::new).collect()
+    }
+
+    /// List the symbols other libraries can bind to, along with demangled C++ names when the demangle feature is
+    /// enabled
+    pub fn exported_symbol_names(&mut self) -> Vec<SymbolName> {
+        self.exported_symbols().into_iter().map(|(symbol_name, _)| SymbolName::new(symbol_name)).collect()
+    }
+
+    /// List the symbols this library needs from its dependencies or overrides
+    pub fn imported_symbols(&mut self) -> Vec<String> {
+        let Some((symbol_table, symbol_string_table)) =
+            dynamic::symbol_table(&mut self.elf_file, self.dynamic_tables.as_ref())
+        else {
+            return Vec::new();
+        };
+        symbol_table
+            .iter()
+            .filter(|symbol| symbol.st_shndx == SHN_UNDEF && symbol.st_bind() != STB_LOCAL && symbol.st_name != 0)
+            .filter_map(|symbol| symbol_string_table.get(symbol.st_name as usize).ok())
+            .map(|symbol_name| symbol_name.to_owned())
+            .collect()
+    }
+
+    /// The address currently stored in each GOT slot, alongside the symbol it is for. Slots bound lazily still point
+    /// back into the PLT until their first call. Empty until initialize() has been called
+    pub fn got_bindings(&mut self) -> Vec<(String, usize)> {
+        if !self.have_been_initialized {
+            return Vec::new();
+        }
+        let mut bindings = Vec::new();
+        for relocation in self.relocations().into_iter().filter(|r| r.symbol != 0 && r.is_got_slot()) {
+            let Some(symbol_name) = self.find_local_symbol_by_index(relocation.symbol, false).and_then(|s| s.name)
+            else {
+                continue;
+            };
+            let address = unsafe { *(self.get_offset(relocation.offset) as *const usize) };
+            bindings.push((symbol_name, address));
+        }
+        bindings
+    }
+
+    pub fn override_symbol(&mut self, symbol_name: &str, new_value: Option<*const ()>) {
+        trace!(target: &self.log_target, "Overriding symbol {symbol_name} with {new_value:?}");
+        self.symbol_overrides.insert(symbol_name.to_owned(), new_value.map(|v| v as usize));
+    }
+
+    /// Override a batch of symbols at once, e.g. one of the sets in libc_overrides
+    pub fn override_symbols(&mut self, overrides: &[(&str, Option<*const ()>)]) {
+        for &(symbol_name, new_value) in overrides {
+            self.override_symbol(symbol_name, new_value);
+        }
+    }
+
+    /// Route the library's calls to common libc syscall wrappers (open, read, write, socket, ...) through `handler`,
+    /// which can let each call through or answer it itself. Raw syscall instructions and libc functions called from
+    /// dependencies aren't seen. The handler is shared by the whole process, installing another replaces it for every
+    /// library already intercepting
+    pub fn intercept_syscalls<F: FnMut(&SyscallArgs) -> SyscallResult + Send + 'static>(&mut self, handler: F) {
+        syscalls::set_handler(Some(Box::new(handler)));
+        for (symbol_name, wrapper) in syscalls::wrappers() {
+            self.override_symbol_live(symbol_name, Some(wrapper));
+        }
+    }
+
+    /// Remove the syscall handler. Calls from any library intercepting syscalls go straight to libc again
+    pub fn stop_intercepting_syscalls(&mut self) {
+        syscalls::set_handler(None);
+    }
+
+    /// Route the library's malloc, calloc, realloc, free and posix_memalign through wrappers that count bytes and
+    /// calls before forwarding to the host allocator. The counters are shared by every library tracking allocations
+    pub fn track_allocations(&mut self) -> AllocTracker {
+        for (symbol_name, wrapper) in alloc::wrappers() {
+            self.override_symbol_live(symbol_name, Some(wrapper));
+        }
+        AllocTracker::new()
+    }
+
+    /// Give a weak import a value to use when nothing defines it, instead of 0. Unlike override_symbol this never
+    /// replaces a real definition, and strong imports of the same name are unaffected. Useful for optional hooks the
+    /// library only calls if they are present
+    pub fn provide_weak_default(&mut self, symbol_name: &str, value: *const ()) {
+        trace!(target: &self.log_target, "Providing weak default {value:?} for {symbol_name}");
+        self.weak_defaults.insert(symbol_name.to_owned(), value as usize);
+    }
+
+    /// Override a symbol only where the library imports a specific version of it, e.g. `memcpy` `GLIBC_2.14`. Other
+    /// versions are bound normally, unless they are overridden with override_symbol
+    pub fn override_versioned_symbol(&mut self, symbol_name: &str, version: &str, new_value: Option<*const ()>) {
+        trace!(target: &self.log_target, "Overriding symbol {symbol_name}@{version} with {new_value:?}");
+        self.versioned_overrides.insert((symbol_name.to_owned(), version.to_owned()), new_value.map(|v| v as usize));
+    }
+
+    /// Override a symbol after initialize() has been called. Any GOT entries that were already resolved for the
+    /// symbol are rewritten, relocations that patch code directly (e.g. R_X86_64_PC32) are left alone
+    pub fn override_symbol_live(&mut self, symbol_name: &str, new_value: Option<*const ()>) {
+        self.override_symbol(symbol_name, new_value);
+        if !self.have_been_initialized {
+            return;
+        }
+        self.bind_symbol(symbol_name);
+    }
+
+    /// Remove an override. If initialize() has been called the GOT entries for the symbol are resolved again without
+    /// it, like override_symbol_live
+    pub fn clear_override(&mut self, symbol_name: &str) {
+        trace!(target: &self.log_target, "Clearing override for symbol {symbol_name}");
+        self.symbol_overrides.remove(symbol_name);
+        if self.have_been_initialized {
+            self.bind_symbol(symbol_name);
+        }
+    }
+
+    /// Resolve a symbol again, consulting the current overrides and dependencies, and rewrite the GOT entries and
+    /// pointers that refer to it. Returns how many were updated
+    pub fn bind_symbol(&mut self, symbol_name: &str) -> usize {
+        let address = self.resolve_binding(symbol_name);
+        self.rewrite_symbol_slots(symbol_name, address.unwrap_or(UNDEFINED_SYMBOL_VALUE))
+    }
+
+    /// Where a call from the library to an imported symbol would go right now, through overrides, the library itself
+    /// and its dependencies, without writing to any GOT slot. Unlike got_bindings() the slot doesn't need to have been
+    /// resolved yet. None if the library doesn't import the symbol or it can't be resolved
+    pub fn import_binding(&mut self, symbol_name: &str) -> Option<usize> {
+        if !self.imported_symbols().iter().any(|name| name == symbol_name) {
+            return None;
+        }
+        self.resolve_binding(symbol_name)
+    }
+
+    // Resolve a symbol by name the same way the GOT slots for it are bound
+    fn resolve_binding(&mut self, symbol_name: &str) -> Option<usize> {
+        match self.symbol_overrides.get(symbol_name) {
+            Some(&overridden_value) => overridden_value,
+            None => {
+                // .hash also lists the undefined symbols the library imports, those have to come from elsewhere
+                let local_symbol =
+                    self.find_local_symbol_by_name(symbol_name, true).filter(|symbol| symbol.shndx != SHN_UNDEF);
+                let symbol = match local_symbol {
+                    Some(symbol) => Some(symbol),
+                    None => self.find_global_symbol(symbol_name, true),
+                };
+                symbol.map(|s| s.address.unwrap_or(self.get_offset(s.value as usize)))
+            },
+        }
+    }
+
+    /// Try again to bind GOT slots that are still unresolved after initialize(), e.g. after adding a dependency or
+    /// symbol provider that defines what was missing. Returns how many slots were bound. Lazily bound PLT slots don't
+    /// need this, they are resolved against whatever is available when first called
+    pub fn rebind_unresolved(&mut self) -> usize {
+        if !self.have_been_initialized {
+            return 0;
+        }
+        let mut unresolved = Vec::new();
+        for relocation in self.relocations().into_iter().filter(|r| r.symbol != 0 && r.is_got_slot()) {
+            let value = unsafe { *(self.get_offset(relocation.offset) as *const usize) };
+            if value != 0 && value != UNDEFINED_SYMBOL_VALUE {
                 continue;
             }
-            match locate::locate_library_internal(&lib_name, None, parent_dir.clone(), dt_runpath.clone()) {
-                Some(lib_path) => {
-                    let dependency = JNI::new(lib_path).ok();
-                    debug!(target: &self.name, "Found dependency {lib_name} - {:?}", dependency.as_ref().map(|d| d.path.to_owned()));
-                    self.dependencies.insert(lib_name, dependency.map(Mutex::new).map(Arc::new));
+            let Some(symbol_name) = self.find_local_symbol_by_index(relocation.symbol, false).and_then(|s| s.name)
+            else {
+                continue;
+            };
+            if !unresolved.contains(&symbol_name) {
+                unresolved.push(symbol_name);
+            }
+        }
+        let mut rebound = 0;
+        for symbol_name in unresolved.iter() {
+            match self.resolve_binding(symbol_name) {
+                Some(address) if address != 0 => rebound += self.rewrite_symbol_slots(symbol_name, address),
+                _ => trace!(target: &self.log_target, "{symbol_name} is still unresolved"),
+            }
+        }
+        debug!(target: &self.log_target, "Rebound {rebound} slots for {} unresolved symbols", unresolved.len());
+        rebound
+    }
+
+    /// Override a symbol only while `f` runs. The previous override, or lack of one, is restored afterwards even if
+    /// `f` panics
+    pub fn with_override<R>(
+        &mut self, symbol_name: &str, new_value: Option<*const ()>, f: impl FnOnce(&mut JNI) -> R,
+    ) -> R {
+        struct RestoreOverride<'a> {
+            jni: &'a mut JNI,
+            symbol_name: &'a str,
+            previous: Option<Option<usize>>,
+        }
+        impl Drop for RestoreOverride<'_> {
+            fn drop(&mut self) {
+                match self.previous {
+                    Some(previous) => self.jni.override_symbol_live(self.symbol_name, previous.map(|v| v as *const ())),
+                    None => self.jni.clear_override(self.symbol_name),
+                }
+            }
+        }
+
+        let previous = self.symbol_overrides.get(symbol_name).copied();
+        self.override_symbol_live(symbol_name, new_value);
+        let mut guard = RestoreOverride { jni: self, symbol_name, previous };
+        f(&mut *guard.jni)
+    }
+
+    // Point the GOT entries for a symbol at a new address
+    fn rewrite_symbol_slots(&mut self, symbol_name: &str, address: usize) -> usize {
+        let Some((symbol_table, symbol_string_table)) =
+            dynamic::symbol_table(&mut self.elf_file, self.dynamic_tables.as_ref())
+        else {
+            return 0;
+        };
+        let symbol_indexes: Vec<u32> = symbol_table
+            .iter()
+            .enumerate()
+            .filter(|(_, symbol)| {
+                symbol_string_table.get(symbol.st_name as usize).is_ok_and(|name| name == symbol_name)
+            })
+            .map(|(index, _)| index as u32)
+            .collect();
+        if symbol_indexes.is_empty() {
+            return 0;
+        }
+
+        // GOT entries usually live in the RELRO region which is read only after initialization
+        let reprotect_relro = match self.mapping.unprotect_relro() {
+            Ok(reprotect) => reprotect,
+            Err(error) => {
+                warn!(target: &self.log_target, "Failed to make RELRO writable - {error}");
+                return 0;
+            },
+        };
+        let mut rewritten = 0;
+        for relocation in self.relocations().into_iter().filter(|r| symbol_indexes.contains(&r.symbol)) {
+            let target_addr = self.get_offset(relocation.offset);
+            #[cfg(target_arch = "x86_64")]
+            let target_value = match relocation.rel_type {
+                elf::abi::R_X86_64_GLOB_DAT | elf::abi::R_X86_64_JUMP_SLOT => address,
+                elf::abi::R_X86_64_64 => match add_addend(address, relocation.addend) {
+                    Some(value) => value,
+                    None => continue,
                 },
-                None => {
-                    debug!(target: &self.name, "Found dependency {lib_name} - None");
-                    self.dependencies.insert(lib_name, None);
+                _ => continue,
+            };
+            #[cfg(target_arch = "aarch64")]
+            let target_value = match relocation.rel_type {
+                elf::abi::R_AARCH64_GLOB_DAT | elf::abi::R_AARCH64_JUMP_SLOT | elf::abi::R_AARCH64_ABS64 => {
+                    match add_addend(address, relocation.addend) {
+                        Some(value) => value,
+                        None => continue,
+                    }
                 },
+                _ => continue,
+            };
+            #[cfg(all(not(target_arch = "x86_64"), not(target_arch = "aarch64")))]
+            let target_value: usize = panic!("Unhandled system architecture");
+            #[cfg(target_pointer_width = "64")]
+            debug!(target: &self.log_target, "Rewriting {relocation:?} at {:#018x} to {:#018x}", target_addr, target_value);
+            #[cfg(not(target_pointer_width = "64"))]
+            debug!(target: &self.log_target, "Rewriting {relocation:?} at {:#010x} to {:#010x}", target_addr, target_value);
+            unsafe { *(target_addr as *mut usize) = target_value };
+            rewritten += 1;
+        }
+        if reprotect_relro {
+            if let Err(error) = self.mapping.protect_relro() {
+                warn!(target: &self.log_target, "Failed to protect RELRO again - {error}");
             }
         }
-        Ok(())
+        rewritten
     }
 
-    pub fn override_symbol(&mut self, symbol_name: &str, new_value: Option<*const ()>) {
-        trace!(target: &self.name, "Overriding symbol {symbol_name} with {new_value:?}");
-        self.symbol_overrides.insert(symbol_name.to_owned(), new_value.map(|v| v as usize));
+    // Every relocation in .rel.dyn, .rel.plt, .rela.dyn and .rela.plt, in that order
+    fn relocations(&mut self) -> Vec<Relocation> {
+        let mut relocations = Vec::new();
+        for section_name in [".rel.dyn", ".rel.plt", ".rela.dyn", ".rela.plt"] {
+            relocations.extend(self.relocation_table(section_name).unwrap_or_default());
+        }
+        relocations
     }
 
+    // The relocations in one of .rel.dyn, .rela.dyn, .rel.plt or .rela.plt. Without section headers the tables are
+    // found through DT_REL, DT_RELA and DT_JMPREL instead
+    fn relocation_table(&mut self, section_name: &str) -> Option<Vec<Relocation>> {
+        if let Some(dynamic_tables) = self.dynamic_tables.as_ref() {
+            return dynamic_tables.relocations(section_name);
+        }
+        let header = *self.elf_file.section_header_by_name(section_name).ok()??;
+        if section_name.starts_with(".rela") {
+            self.elf_file.section_data_as_relas(&header).ok().map(|rela| rela.map(Relocation::from).collect())
+        } else {
+            self.elf_file.section_data_as_rels(&header).ok().map(|rel| rel.map(Relocation::from).collect())
+        }
+    }
+
+    /// Whether initialize() has run, meaning relocations have been applied and constructors called
+    pub fn is_initialized(&self) -> bool {
+        self.have_been_initialized
+    }
+
+    /// Look up a symbol's address and size. The library should be initialized first, before then its imports are
+    /// unbound and relocated data still holds file contents, so calling into it will crash somewhere deep inside
     pub fn get_symbol(&mut self, symbol_name: &str) -> Option<(*const (), u64)> {
+        if self.unloaded {
+            warn!(target: &self.log_target, "Looking up {symbol_name} after the library has been unloaded");
+            return None;
+        }
+        if !self.have_been_initialized {
+            warn!(target: &self.log_target, "Looking up {symbol_name} before the library has been initialized");
+        }
         let mut symbol = self.find_local_symbol_by_name(symbol_name, false);
         if symbol.is_none() {
             symbol = self.find_global_symbol(symbol_name, false);
@@ -206,8 +1094,450 @@ impl JNI {
         symbol.map(|symbol| (self.get_offset(symbol.value as usize) as *const (), symbol.size))
     }
 
+    /// Like get_symbol but only ever returns this library's own definition, ignoring overrides and dependencies. Gets
+    /// the original implementation of a symbol even after it has been overridden or interposed
+    pub fn get_local_symbol(&mut self, symbol_name: &str) -> Option<(*const (), u64)> {
+        if self.unloaded {
+            warn!(target: &self.log_target, "Looking up {symbol_name} after the library has been unloaded");
+            return None;
+        }
+        if !self.have_been_initialized {
+            warn!(target: &self.log_target, "Looking up {symbol_name} before the library has been initialized");
+        }
+        let symbol = self.find_local_symbol_by_name(symbol_name, false).filter(|symbol| symbol.shndx != SHN_UNDEF)?;
+        Some((self.get_offset(symbol.value as usize) as *const (), symbol.size))
+    }
+
+    /// Collect every exported symbol from this library, its preloaded libraries and all of their dependencies into one
+    /// table for get_symbol_flat(). This ignores the normal binding rules, the first global definition found in the
+    /// same order find_global_symbol searches wins, falling back to the first weak one. Dependencies should be loaded
+    /// first. Call it again to pick up libraries added since
+    pub fn flatten_namespace(&mut self) {
+        let mut symbols = HashMap::new();
+        let mut weak_symbols = Vec::new();
+        let mut add_symbols = |exported: Vec<(String, LinkingSymbol)>| {
+            for (symbol_name, symbol) in exported {
+                let Some(address) = symbol.address else {
+                    continue;
+                };
+                let weak = symbol.binding == STB_WEAK;
+                match symbols.get(&symbol_name) {
+                    Some(_) if !weak_symbols.contains(&symbol_name) || weak => continue,
+                    Some(_) => weak_symbols.retain(|n| n != &symbol_name),
+                    None if weak => weak_symbols.push(symbol_name.clone()),
+                    None => {},
+                }
+                symbols.insert(symbol_name, (address, symbol.size));
+            }
+        };
+
+        add_symbols(self.exported_symbols());
+        let mut visited = Vec::new();
+        let mut pending: Vec<_> = self.preloaded.iter().cloned().collect();
+        pending.extend(self.dependencies.iter().filter_map(|(_, dependency)| dependency.clone()));
+        let mut next = 0;
+        while next < pending.len() {
+            let lib = pending[next].clone();
+            next += 1;
+            if visited.iter().any(|v| Arc::ptr_eq(v, &lib)) {
+                continue;
+            }
+            visited.push(lib.clone());
+            // A library that is already locked is further up the graph from us, so its symbols are already included
+            let Ok(mut lib) = lib.try_lock() else {
+                continue;
+            };
+            add_symbols(lib.exported_symbols());
+            pending.extend(lib.dependencies.iter().filter_map(|(_, dependency)| dependency.clone()));
+        }
+        debug!(target: &self.log_target, "Flattened {} symbols from {} libraries", symbols.len(), visited.len() + 1);
+        self.flat_namespace = Some(symbols);
+    }
+
+    /// Look up a symbol in the table built by flatten_namespace()
+    pub fn get_symbol_flat(&self, symbol_name: &str) -> Option<(*const (), u64)> {
+        let &(address, size) = self.flat_namespace.as_ref()?.get(symbol_name)?;
+        Some((address as *const (), size))
+    }
+
+    /// This library and every library it pulled in (preloaded, dependencies and their dependencies, libraries it
+    /// dlopen'd), each with where it is mapped. Libraries locked by another thread are skipped
+    pub fn loaded_map(&self) -> Vec<LoadedLib> {
+        let mut loaded = vec![self.loaded_lib()];
+        let mut visited = Vec::new();
+        let mut pending: Vec<_> = self.preloaded.iter().cloned().collect();
+        pending.extend(self.dependencies.iter().filter_map(|(_, dependency)| dependency.clone()));
+        #[cfg(feature = "inline-asm")]
+        pending.extend(self.dlopen_dependencies.values().filter_map(|dependency| dependency.clone()));
+        let mut next = 0;
+        while next < pending.len() {
+            let lib = pending[next].clone();
+            next += 1;
+            if visited.iter().any(|v| Arc::ptr_eq(v, &lib)) {
+                continue;
+            }
+            visited.push(lib.clone());
+            let Ok(lib) = lib.try_lock() else {
+                continue;
+            };
+            loaded.push(lib.loaded_lib());
+            pending.extend(lib.dependencies.iter().filter_map(|(_, dependency)| dependency.clone()));
+            #[cfg(feature = "inline-asm")]
+            pending.extend(lib.dlopen_dependencies.values().filter_map(|dependency| dependency.clone()));
+        }
+        loaded
+    }
+
+    fn loaded_lib(&self) -> LoadedLib {
+        LoadedLib { name: self.name.clone(), path: self.path.clone(), base: self.mapping.base, size: self.mapping.size }
+    }
+
+    // Symbols defined in this library that other libraries can bind to
+    fn exported_symbols(&mut self) -> Vec<(String, LinkingSymbol)> {
+        let Some((symbol_table, symbol_string_table)) =
+            dynamic::symbol_table(&mut self.elf_file, self.dynamic_tables.as_ref())
+        else {
+            return Vec::new();
+        };
+        symbol_table
+            .iter()
+            .filter(|symbol| symbol.st_shndx != SHN_UNDEF && symbol.st_bind() != STB_LOCAL && symbol.st_name != 0)
+            .filter(|symbol| symbol.st_symtype() != STT_TLS)
+            .filter_map(|symbol| {
+                let symbol_name = symbol_string_table.get(symbol.st_name as usize).ok()?.to_owned();
+                let linking_symbol = LinkingSymbol::from(
+                    &symbol,
+                    Some(symbol_name.clone()),
+                    self.mapping.base,
+                    self.base_virtual_address,
+                );
+                linking_symbol.is_exported().then_some((symbol_name, linking_symbol))
+            })
+            .collect()
+    }
+
+    /// Resolve the native implementation of a Java method, e.g. `jni_method("com.example.Foo", "bar")` looks up
+    /// `Java_com_example_Foo_bar`
+    pub fn jni_method(&mut self, class: &str, method: &str) -> Option<*const ()> {
+        let symbol_name = mangle::jni_symbol_name(class, method);
+        trace!(target: &self.log_target, "Looking for JNI method {symbol_name}");
+        self.get_symbol(&symbol_name).map(|(address, _)| address)
+    }
+
+    /// Call the library's JNI_OnLoad if it has one, returning the JNI version it needs. The VM is remembered so
+    /// JNI_OnUnload can be called with it when the library is dropped. Only the library's own JNI_OnLoad is used, never
+    /// one from a dependency
+    pub fn call_jni_onload(&mut self, vm: *mut c_void) -> Option<i32> {
+        self.java_vm = Some(vm as usize);
+        let (address, _) = self.get_local_symbol("JNI_OnLoad")?;
+        debug!(target: &self.log_target, "Calling JNI_OnLoad");
+        let jni_onload: extern "C" fn(*mut c_void, *mut c_void) -> i32 = unsafe { std::mem::transmute(address) };
+        Some(jni_onload(vm, std::ptr::null_mut()))
+    }
+
+    /// Call the library's JNI_OnUnload if it has one, never one from a dependency. This happens automatically on drop
+    /// if JNI_OnLoad was called. The VM remembered by call_jni_onload is forgotten even if there is no JNI_OnUnload, so
+    /// it won't be called again on drop
+    pub fn call_jni_onunload(&mut self, vm: *mut c_void) {
+        self.java_vm = None;
+        let Some((address, _)) = self.get_local_symbol("JNI_OnUnload") else {
+            return;
+        };
+        debug!(target: &self.log_target, "Calling JNI_OnUnload");
+        let jni_onunload: extern "C" fn(*mut c_void, *mut c_void) = unsafe { std::mem::transmute(address) };
+        jni_onunload(vm, std::ptr::null_mut());
+    }
+
+    /// Run `f` on a new stack of `stack_size` bytes instead of the current one, e.g. to call into a library that needs
+    /// more stack than a worker thread has. The stack has a guard page below it and is freed once `f` returns. Panics
+    /// in `f` are carried back to the caller
+    pub fn call_on_stack<R>(&self, f: impl FnOnce() -> R, stack_size: usize) -> Result<R, Error> {
+        trace!(target: &self.log_target, "Calling on a {stack_size} byte stack");
+        stack::call_on_stack(f, stack_size).map_err(Error::MemoryMapFailed)
+    }
+
+    /// Resolve a symbol as a function that keeps the library alive while it's in use
+    ///
+    /// # Safety
+    /// `F` must be a function pointer type matching the symbol's real signature
+    pub unsafe fn get_callable<F: Copy>(jni: &Arc<Mutex<Box<JNI>>>, symbol_name: &str) -> Option<Callable<F>> {
+        let (address, _) = jni.lock().unwrap().get_symbol(symbol_name)?;
+        Some(Callable::new(jni.clone(), address))
+    }
+
+    /// The virtual address a symbol is defined at in the file, the same kind of value as a hardcoded offset passed to
+    /// get_offset(), e.g. `lib.get_offset(lib.offset_of("encrypt")?)`. None for undefined symbols. Overrides are
+    /// ignored, this always describes the file
+    pub fn offset_of(&mut self, symbol_name: &str) -> Option<usize> {
+        let symbol = self.find_local_symbol_by_name(symbol_name, false)?;
+        match symbol.value {
+            0 => None,
+            value => Some(value as usize),
+        }
+    }
+
+    /// Read a NUL terminated string at a virtual address in the file, e.g. a version banner. The read never goes past
+    /// the end of the readable segment containing it, None if there is no NUL before then. Invalid UTF-8 is replaced
+    pub fn read_cstr(&self, offset: usize) -> Option<String> {
+        if self.unloaded {
+            return None;
+        }
+        let segment = self.elf_file.segments().iter().find(|s| {
+            s.p_type == PT_LOAD
+                && s.p_flags & PF_R == PF_R
+                && s.p_vaddr as usize <= offset
+                && offset < (s.p_vaddr + s.p_memsz) as usize
+        })?;
+        let segment_end = (segment.p_vaddr + segment.p_memsz) as usize;
+        let address = self.checked_offset(offset)?;
+        let bytes = unsafe { std::slice::from_raw_parts(address as *const u8, segment_end - offset) };
+        let length = bytes.iter().position(|&b| b == 0)?;
+        Some(String::from_utf8_lossy(&bytes[..length]).into_owned())
+    }
+
+    /// Read the string stored in an exported char array, see read_cstr(). For a `const char *` symbol this reads the
+    /// pointer's bytes, not the string it points to
+    pub fn symbol_cstr(&mut self, symbol_name: &str) -> Option<String> {
+        let offset = self.offset_of(symbol_name)?;
+        self.read_cstr(offset)
+    }
+
+    /// Record where the library was loaded somewhere else, e.g. the base address from a device crash log, so
+    /// translate_from_original can map addresses from there into this mapping
+    pub fn set_original_base(&mut self, original_base: usize) {
+        self.original_base = Some(original_base);
+    }
+
+    /// Convert an absolute address from where the library was originally loaded into the same location in this
+    /// mapping. Without set_original_base the address is treated as a virtual address from the file
+    pub fn translate_from_original(&self, device_addr: usize) -> usize {
+        let original_base = self.original_base.unwrap_or(self.base_virtual_address);
+        self.get_offset(device_addr.wrapping_sub(original_base).wrapping_add(self.base_virtual_address))
+    }
+
+    /// Convert a virtual address from the ELF file into a pointer in the loaded library. `offset` must be at least
+    /// virtual_base(), use checked_offset when that isn't certain
     pub fn get_offset(&self, offset: usize) -> usize {
-        self.mapping.base + offset - self.base_virtual_address
+        debug_assert!(
+            offset >= self.base_virtual_address,
+            "Virtual address {:#x} is below the lowest loaded address {:#x}",
+            offset,
+            self.base_virtual_address
+        );
+        self.mapping.base.wrapping_add(offset).wrapping_sub(self.base_virtual_address)
+    }
+
+    /// Same as get_offset, but returns None instead of a wild pointer when `offset` is below virtual_base() or the
+    /// result doesn't fit in a usize
+    pub fn checked_offset(&self, offset: usize) -> Option<usize> {
+        let relative_offset = offset.checked_sub(self.base_virtual_address)?;
+        self.mapping.base.checked_add(relative_offset)
+    }
+
+    /// Lowest virtual address of any loaded segment in the file
+    pub fn virtual_base(&self) -> usize {
+        self.base_virtual_address
+    }
+
+    /// Virtual address just past the end of the highest loaded segment in the file, including zero-initialized data
+    pub fn virtual_end(&self) -> usize {
+        let segments = self.elf_file.segments().iter().filter(|s| s.p_type == PT_LOAD);
+        segments.map(|s| (s.p_vaddr + s.p_memsz) as usize).max().unwrap_or(self.base_virtual_address)
+    }
+
+    /// Translate a virtual address from the file (e.g. from a disassembler) to where it is loaded, None if it is below
+    /// the lowest loaded address
+    pub fn file_addr_to_runtime(&self, vaddr: usize) -> Option<usize> {
+        self.checked_offset(vaddr)
+    }
+
+    /// Translate a loaded address back to the virtual address in the file, None if it's outside the mapping
+    pub fn runtime_to_file_addr(&self, addr: usize) -> Option<usize> {
+        let relative_offset = addr.checked_sub(self.mapping.base)?;
+        if relative_offset >= self.mapping.size {
+            return None;
+        }
+        self.base_virtual_address.checked_add(relative_offset)
+    }
+
+    /// Address of a thread-local variable exported by the library, as seen from the current thread. Only the thread
+    /// that called initialize() has the library's TLS block, so this is None on any other thread
+    pub fn tls_symbol_addr(&mut self, symbol_name: &str) -> Option<usize> {
+        let symbol = self.find_local_symbol_by_name(symbol_name, false)?;
+        if symbol.sym_type != STT_TLS || symbol.shndx == SHN_UNDEF {
+            return None;
+        }
+        let Some(tls_block) = self.tls_block.as_ref() else {
+            warn!(target: &self.log_target, "Looking up TLS symbol {symbol_name} without a TLS block, initialize first");
+            return None;
+        };
+        let address = tls_block.variable_address(symbol.value as usize);
+        if address.is_none() {
+            warn!(target: &self.log_target, "TLS symbol {symbol_name} is only available on the thread that initialized the library");
+        }
+        address
+    }
+
+    /// Describe the thread local storage the library needs, if any, based on its PT_TLS program header
+    pub fn tls_info(&self) -> Option<TlsInfo> {
+        let tls_header = self.elf_file.segments().iter().find(|&s| s.p_type == PT_TLS)?;
+        Some(TlsInfo {
+            template_size: tls_header.p_filesz as usize,
+            total_size: tls_header.p_memsz as usize,
+            alignment: tls_header.p_align as usize,
+        })
+    }
+
+    /// Runtime address ranges of the loaded segments and of the sections tools usually care about
+    pub fn layout(&mut self) -> Layout {
+        let segments = self
+            .elf_file
+            .segments()
+            .iter()
+            .filter(|s| s.p_type == PT_LOAD)
+            .map(|s| SegmentLayout {
+                start: self.get_offset(s.p_vaddr as usize),
+                end: self.get_offset((s.p_vaddr + s.p_memsz) as usize),
+                readable: s.p_flags & PF_R == PF_R,
+                writable: s.p_flags & PF_W == PF_W,
+                executable: s.p_flags & PF_X == PF_X,
+            })
+            .collect();
+        let mut sections = Vec::new();
+        for name in [".text", ".data", ".bss", ".got", ".got.plt"] {
+            if let Ok(Some(&header)) = self.elf_file.section_header_by_name(name) {
+                sections.push(SectionLayout {
+                    name: name.to_owned(),
+                    start: self.get_offset(header.sh_addr as usize),
+                    end: self.get_offset((header.sh_addr + header.sh_size) as usize),
+                });
+            }
+        }
+        Layout { segments, sections }
+    }
+
+    /// Compare the writable segments of two instances of the same library, e.g. after calling a function on one of
+    /// them, and list every byte that differs as (offset from the mapping base, value here, value in `other`). Both
+    /// instances must have the same layout, i.e. be loaded from the same file, otherwise this returns None
+    pub fn diff_writable(&self, other: &JNI) -> Option<Vec<(usize, u8, u8)>> {
+        let writable_segments = |jni: &JNI| -> Vec<(usize, usize)> {
+            jni.elf_file
+                .segments()
+                .iter()
+                .filter(|s| s.p_type == PT_LOAD && s.p_flags & PF_W == PF_W)
+                .map(|s| (s.p_vaddr as usize - jni.base_virtual_address, s.p_memsz as usize))
+                .collect()
+        };
+        let segments = writable_segments(self);
+        if segments != writable_segments(other) || self.mapping.size != other.mapping.size {
+            warn!(target: &self.log_target, "Can't compare with {}, the layouts are different", other.log_target);
+            return None;
+        }
+
+        let mut differences = Vec::new();
+        for (offset, size) in segments {
+            let ours = unsafe { std::slice::from_raw_parts((self.mapping.base + offset) as *const u8, size) };
+            let theirs = unsafe { std::slice::from_raw_parts((other.mapping.base + offset) as *const u8, size) };
+            differences.extend(
+                ours.iter()
+                    .zip(theirs.iter())
+                    .enumerate()
+                    .filter(|(_, (a, b))| a != b)
+                    .map(|(i, (&a, &b))| (offset + i, a, b)),
+            );
+        }
+        trace!(target: &self.log_target, "Found {} bytes that differ from {}", differences.len(), other.log_target);
+        Some(differences)
+    }
+
+    /// Describe the relocations in the file without applying any of them, to check up front whether initialize() can
+    /// handle the library
+    pub fn relocation_sections(&mut self) -> RelocationSummary {
+        let mut sections = Vec::new();
+        for name in [".rel.dyn", ".rela.dyn", ".rel.plt", ".rela.plt"] {
+            if let Some(relocations) = self.relocation_table(name) {
+                sections.push((name.to_owned(), relocations.len()));
+            }
+        }
+        if let Ok(Some(&header)) = self.elf_file.section_header_by_name(".relr.dyn") {
+            let entries = if header.sh_entsize == 0 {
+                0
+            } else {
+                (header.sh_size / header.sh_entsize) as usize
+            };
+            sections.push((".relr.dyn".to_owned(), entries));
+        }
+        let mut types: Vec<(u32, usize)> = Vec::new();
+        let mut unsupported_types = Vec::new();
+        for relocation in self.relocations() {
+            match types.iter_mut().find(|(rel_type, _)| *rel_type == relocation.rel_type) {
+                Some((_, count)) => *count += 1,
+                None => types.push((relocation.rel_type, 1)),
+            }
+            if !relocation.is_supported() && !unsupported_types.contains(&relocation.rel_type) {
+                unsupported_types.push(relocation.rel_type);
+            }
+        }
+        types.sort();
+        unsupported_types.sort();
+        RelocationSummary { sections, types, unsupported_types }
+    }
+
+    /// Check everything this build needs to fully load the library, so an unknown library can be rejected up front
+    /// with a reason instead of crashing part way through initialize()
+    pub fn compatibility_report(&mut self) -> CompatibilityReport {
+        let mut unsupported = Vec::new();
+
+        #[cfg(target_arch = "x86_64")]
+        let host_machine = Some(elf::abi::EM_X86_64);
+        #[cfg(target_arch = "aarch64")]
+        let host_machine = Some(elf::abi::EM_AARCH64);
+        #[cfg(all(not(target_arch = "x86_64"), not(target_arch = "aarch64")))]
+        let host_machine = None;
+        #[cfg(target_pointer_width = "64")]
+        let host_class = Class::ELF64;
+        #[cfg(not(target_pointer_width = "64"))]
+        let host_class = Class::ELF32;
+        let architecture_matches = host_machine == Some(self.machine) && host_class == self.class;
+        if !architecture_matches {
+            let reason = format!(
+                "library is {:?} machine {}, this build is {host_class:?} machine {host_machine:?}",
+                self.class, self.machine
+            );
+            unsupported.push(("architecture".to_owned(), reason));
+        }
+
+        let relocations = self.relocation_sections();
+        for rel_type in relocations.unsupported_types.iter() {
+            let reason = "initialize() can't apply it on this architecture".to_owned();
+            unsupported.push((format!("relocation type {rel_type:#x}"), reason));
+        }
+        let uses_relr = relocations.sections.iter().any(|(name, entries)| name == ".relr.dyn" && *entries > 0);
+        if uses_relr {
+            unsupported.push((".relr.dyn".to_owned(), "packed relative relocations aren't supported".to_owned()));
+        }
+
+        let uses_ifunc = self.relocations().iter().any(Relocation::is_irelative);
+        if uses_ifunc && !self.call_ifunc_resolvers {
+            let reason = "loaded without permission to run the library's IFUNC resolvers".to_owned();
+            unsupported.push(("IFUNC".to_owned(), reason));
+        }
+
+        let tls = self.tls_info();
+        let mut required_versions: Vec<_> = self.required_versions().into_iter().collect();
+        required_versions.sort();
+        let lazy_binding =
+            cfg!(feature = "inline-asm") && matches!(self.elf_file.section_header_by_name(".got.plt"), Ok(Some(_)));
+        CompatibilityReport {
+            architecture_matches,
+            relocations,
+            tls,
+            uses_ifunc,
+            uses_relr,
+            required_versions,
+            lazy_binding,
+            unsupported,
+        }
     }
 
     #[cfg(feature = "inline-asm")]
@@ -230,20 +1560,54 @@ impl JNI {
         self.dlopen_dependencies.insert(name.to_string(), lib);
     }
 
+    /// Record every dlopen, dlsym and dlclose call the library makes, retrieved with dlfcn_trace(). Turning it off
+    /// discards what has been recorded
     #[cfg(feature = "inline-asm")]
-    pub(crate) fn dlopen(&mut self, filename: &str, flags: i32) -> Option<*const JNI> {
-        debug!(target: &self.name, r#"dlopen("{filename}", {flags})"#);
+    pub fn record_dlfcn(&mut self, record: bool) {
+        self.dlfcn_trace = record.then(Vec::new);
+    }
 
-        if !self.dlopen_dependencies.contains_key(filename) {
-            let mut file_path = std::path::PathBuf::from(filename);
-            if !file_path.exists() {
-                file_path = locate::locate_library(filename, None)?;
-            }
-            let lib = JNI::new(file_path).ok()?;
-            self.add_dependency(filename, Some(lib));
+    /// The dlopen, dlsym and dlclose calls recorded since record_dlfcn() was called, in order
+    #[cfg(feature = "inline-asm")]
+    pub fn dlfcn_trace(&self) -> &[DlfcnEvent] {
+        self.dlfcn_trace.as_deref().unwrap_or_default()
+    }
+
+    #[cfg(feature = "inline-asm")]
+    fn record_dlfcn_event(&mut self, event: DlfcnEvent) {
+        if let Some(dlfcn_trace) = self.dlfcn_trace.as_mut() {
+            dlfcn_trace.push(event);
         }
+    }
+
+    #[cfg(feature = "inline-asm")]
+    pub(crate) fn dlopen(&mut self, filename: &str, flags: i32) -> Option<*const JNI> {
+        debug!(target: &self.log_target, r#"dlopen("{filename}", {flags})"#);
+        let handle = self.open_dlopen_dependency(filename);
+        let event = DlfcnEvent::Dlopen { filename: filename.to_owned(), flags, handle: handle.map(|h| h as usize) };
+        self.record_dlfcn_event(event);
+        handle
+    }
 
-        let lib = self.dlopen_dependencies.get(filename)?;
+    #[cfg(feature = "inline-asm")]
+    fn open_dlopen_dependency(&mut self, filename: &str) -> Option<*const JNI> {
+        // Libraries already opened under their soname are reused, like the system loader does
+        let key =
+            self.dlopen_dependencies.iter().find(|(key, lib)| dependency_matches(filename, key, lib)).map(|(k, _)| k);
+        let key = match key {
+            Some(key) => key.clone(),
+            None => {
+                let mut file_path = std::path::PathBuf::from(filename);
+                if !file_path.exists() {
+                    file_path = locate::locate_library(filename, None)?;
+                }
+                let lib = JNI::new(file_path).ok()?;
+                self.add_dlopen_dependency(filename, Some(lib));
+                filename.to_owned()
+            },
+        };
+
+        let lib = self.dlopen_dependencies.get(&key)?;
         match lib {
             Some(lib) => {
                 let lib = lib.lock().ok()?;
@@ -256,9 +1620,22 @@ impl JNI {
     #[cfg(feature = "inline-asm")]
     pub(crate) fn dlsym(&mut self, handle: &mut JNI, symbol: &str) -> Option<usize> {
         #[cfg(target_pointer_width = "64")]
-        debug!(target: &self.name, r#"dlsym({:#018x} ({}), "{symbol}")"#, handle.mapping.base, handle.name);
+        debug!(target: &self.log_target, r#"dlsym({:#018x} ({}), "{symbol}")"#, handle.mapping.base, handle.log_target);
         #[cfg(not(target_pointer_width = "64"))]
-        debug!(target: &self.name, r#"dlsym({:#010x} ({}), "{symbol}")"#, handle.mapping.base, handle.name);
+        debug!(target: &self.log_target, r#"dlsym({:#010x} ({}), "{symbol}")"#, handle.mapping.base, handle.log_target);
+        let address = self.resolve_dlsym(handle, symbol);
+        let event = DlfcnEvent::Dlsym {
+            handle: handle as *const JNI as usize,
+            library: handle.name.clone(),
+            symbol: symbol.to_owned(),
+            address,
+        };
+        self.record_dlfcn_event(event);
+        address
+    }
+
+    #[cfg(feature = "inline-asm")]
+    fn resolve_dlsym(&mut self, handle: &mut JNI, symbol: &str) -> Option<usize> {
         let local_symbol = handle.find_local_symbol_by_name(symbol, true)?;
         if local_symbol.address.is_some() {
             return local_symbol.address;
@@ -279,24 +1656,115 @@ impl JNI {
 
     #[cfg(feature = "inline-asm")]
     pub(crate) fn dlclose(&mut self, handle: &mut JNI) -> i32 {
-        debug!(target: &self.name, r#"dlclose("{}")"#, handle.name);
+        debug!(target: &self.log_target, r#"dlclose("{}")"#, handle.log_target);
+        let event = DlfcnEvent::Dlclose { handle: handle as *const JNI as usize, library: handle.name.clone() };
+        self.record_dlfcn_event(event);
         0
     }
 
+    /// Whether PT_GNU_STACK asks for an executable stack. ld.so would make the stack executable, we never do
+    pub fn requires_executable_stack(&self) -> bool {
+        self.elf_file.segments().iter().any(|s| s.p_type == PT_GNU_STACK && s.p_flags & PF_X == PF_X)
+    }
+
+    /// Make initialize() fail instead of only warning when the library asks for an executable stack
+    pub fn set_strict_executable_stack(&mut self, strict: bool) {
+        self.strict_executable_stack = strict;
+    }
+
+    /// By default relocations that can't be applied are skipped and reported by relocation_errors(). In strict mode
+    /// initialize() fails instead
+    pub fn set_strict_relocations(&mut self, strict: bool) {
+        self.strict_relocations = strict;
+    }
+
+    /// Relocations that couldn't be applied by initialize()
+    pub fn relocation_errors(&self) -> &[RelocationError] {
+        &self.relocation_errors
+    }
+
+    /// Describe a relocation error with everything known about it, including the name of the symbol it refers to,
+    /// the section it came from and its index in that section, e.g. for reporting an unsupported relocation type
+    pub fn describe_relocation_error(&mut self, error: &RelocationError) -> String {
+        let symbol_name = match error.symbol {
+            0 => None,
+            index => self.find_local_symbol_by_index(index, false).and_then(|s| s.name),
+        };
+        let mut location = None;
+        for section_name in [".rel.dyn", ".rel.plt", ".rela.dyn", ".rela.plt"] {
+            let Some(relocations) = self.relocation_table(section_name) else {
+                continue;
+            };
+            let index = relocations
+                .iter()
+                .position(|r| r.offset == error.offset && r.rel_type == error.rel_type && r.symbol == error.symbol);
+            if let Some(index) = index {
+                location = Some(format!("{section_name}[{index}]"));
+                break;
+            }
+        }
+
+        #[cfg(target_pointer_width = "64")]
+        let mut description = format!("relocation type {:#x} at {:#018x}", error.rel_type, error.offset);
+        #[cfg(not(target_pointer_width = "64"))]
+        let mut description = format!("relocation type {:#x} at {:#010x}", error.rel_type, error.offset);
+        if let Some(location) = location {
+            description += &format!(" ({location})");
+        }
+        match symbol_name {
+            Some(symbol_name) => description += &format!(" for symbol {symbol_name} (#{})", error.symbol),
+            None if error.symbol != 0 => description += &format!(" for symbol #{}", error.symbol),
+            None => {},
+        }
+        description += match error.kind {
+            RelocationErrorKind::Unsupported => " - unsupported",
+            RelocationErrorKind::Overflow => " - value out of range",
+        };
+        description
+    }
+
+    /// By default a dependency that is found but fails to load is skipped and reported by dependency_errors(). In
+    /// strict mode load_dependencies() fails with its error instead. Dependencies that can't be found are always
+    /// skipped
+    pub fn set_strict_dependencies(&mut self, strict: bool) {
+        self.strict_dependencies = strict;
+    }
+
+    /// Dependencies that load_dependencies() found but couldn't load, along with why
+    pub fn dependency_errors(&self) -> &[(String, Error)] {
+        &self.dependency_errors
+    }
+
     pub fn initialize(&mut self) -> Result<(), Error> {
+        if self.unloaded {
+            return Err(Error::Unloaded);
+        }
         if self.have_been_initialized {
             return Ok(());
         }
+        // Code that expects to run from the stack will crash, and asking for it at all is unusual for modern libraries
+        if self.requires_executable_stack() {
+            if self.strict_executable_stack {
+                error!(target: &self.log_target, "Library requires an executable stack");
+                return Err(Error::ExecutableStack);
+            }
+            warn!(target: &self.log_target, "Library requires an executable stack, the stack will not be made executable");
+        }
         self.have_been_initialized = true;
-        debug!(target: &self.name, "Initializing");
+        debug!(target: &self.log_target, "Initializing");
 
+        for preloaded in self.preloaded.iter() {
+            let mut preloaded = preloaded.lock().unwrap();
+            debug!(target: &self.log_target, "Initializing preloaded library {}", preloaded.log_target);
+            preloaded.initialize()?;
+        }
         for (_, dependency) in self.dependencies.iter() {
             if let Some(dependency) = dependency {
                 // NOTE - Deadlocks
                 // The guard at the top of this function prevents this loop being recursively executed on one instance
                 // If A depends on B and A and B both depend on C, C's lock will be released before B::initialize()
                 let mut dependency = dependency.lock().unwrap();
-                debug!(target: &self.name, "Initializing dependency {}", dependency.name);
+                debug!(target: &self.log_target, "Initializing dependency {}", dependency.log_target);
                 dependency.initialize()?;
             }
         }
@@ -311,121 +1779,195 @@ impl JNI {
         //
         // [1] https://maskray.me/blog/2021-10-31-relative-relocations-and-relr
         let mut relocations = Vec::new();
-        if let Ok(Some(&rel_dyn_header)) = self.elf_file.section_header_by_name(".rel.dyn") {
-            if let Ok(rel_dyn) = self.elf_file.section_data_as_rels(&rel_dyn_header) {
-                let old_len = relocations.len();
-                relocations.extend(rel_dyn.map(Relocation::from));
-                debug!(target: &self.name, "Added {} relocations from .rel.dyn", relocations.len() - old_len);
-            }
-        }
-        if let Ok(Some(&rela_dyn_header)) = self.elf_file.section_header_by_name(".rela.dyn") {
-            if let Ok(rela_dyn) = self.elf_file.section_data_as_relas(&rela_dyn_header) {
-                let old_len = relocations.len();
-                relocations.extend(rela_dyn.map(Relocation::from));
-                debug!(target: &self.name, "Added {} relocations from .rela.dyn", relocations.len() - old_len);
+        for section_name in [".rel.dyn", ".rela.dyn"] {
+            if let Some(dyn_relocations) = self.relocation_table(section_name) {
+                debug!(target: &self.log_target, "Added {} relocations from {section_name}", dyn_relocations.len());
+                relocations.extend(dyn_relocations);
             }
         }
         // Without inline assembly we don't have a PLT trampoline. Resolve all PLT entries now
         // Some binaries may have a .plt section but no .got.plt as all entries are in the .got.
         // In that case resolve all entries now as well
+        // Otherwise only jump slots in .rel.plt/.rela.plt can be left to the trampoline as the PLT stubs index into
+        // those sections. Anything else in them (e.g. IRELATIVE) and any jump slots the linker folded into .rel.dyn
+        // or .rela.dyn (common with -z now) are resolved now
         let got_plt_header = self.elf_file.section_header_by_name(".got.plt")?.copied();
-        if !cfg!(feature = "inline-asm") || got_plt_header.is_none() {
-            let cause = if got_plt_header.is_some() {
-                "Assembly disabled"
-            } else {
-                "No .got.plt section"
-            };
-            if let Ok(Some(&rel_plt_header)) = self.elf_file.section_header_by_name(".rel.plt") {
-                if let Ok(rel_plt) = self.elf_file.section_data_as_rels(&rel_plt_header) {
-                    let old_len = relocations.len();
-                    relocations.extend(rel_plt.map(Relocation::from));
-                    debug!(target: &self.name, "{cause}, added {} relocations from .rel.plt", relocations.len() - old_len);
-                }
-            }
-            if let Ok(Some(&rela_plt_header)) = self.elf_file.section_header_by_name(".rela.plt") {
-                if let Ok(rela_plt) = self.elf_file.section_data_as_relas(&rela_plt_header) {
-                    let old_len = relocations.len();
-                    relocations.extend(rela_plt.map(Relocation::from));
-                    debug!(target: &self.name, "{cause}, added {} relocations from .rela.plt", relocations.len() - old_len);
+        let lazy_binding = cfg!(feature = "inline-asm") && got_plt_header.is_some();
+        let cause = if !lazy_binding && got_plt_header.is_some() {
+            "Assembly disabled"
+        } else if !lazy_binding {
+            "No .got.plt section"
+        } else {
+            "Lazy binding"
+        };
+        let mut lazy_relocations = Vec::new();
+        for section_name in [".rel.plt", ".rela.plt"] {
+            if let Some(plt_relocations) = self.relocation_table(section_name) {
+                let (old_len, old_lazy_len) = (relocations.len(), lazy_relocations.len());
+                for relocation in plt_relocations {
+                    if lazy_binding && relocation.is_jump_slot() {
+                        lazy_relocations.push(relocation);
+                    } else {
+                        relocations.push(relocation);
+                    }
                 }
+                debug!(target: &self.log_target, "{cause}, added {} relocations from {section_name}, deferred {}", relocations.len() - old_len, lazy_relocations.len() - old_lazy_len);
             }
         }
+        // Only the initial-exec TLS model is supported, see tls::TlsBlock for why this only works on one thread
+        if let Some(tls_info) = self.tls_info() {
+            let tls_header = *self.elf_file.segments().iter().find(|&s| s.p_type == PT_TLS).unwrap();
+            let template_addr = self.get_offset(tls_header.p_vaddr as usize);
+            let template = unsafe { std::slice::from_raw_parts(template_addr as *const u8, tls_info.template_size) };
+            self.tls_block = tls::TlsBlock::new(template, tls_info);
+            debug!(target: &self.log_target, "Allocated {} byte TLS block", tls_info.total_size);
+        }
+
+        // Apply relocations in phases like ld.so, rather than in file order. Relative relocations go first as nothing
+        // else depends on them, then anything that binds to a symbol, then TLS descriptors and IRELATIVE last so the
+        // IFUNC resolvers see a fully relocated GOT. The sort is stable so each phase keeps file order
+        relocations.sort_by_key(Relocation::phase);
+
         for relocation in relocations {
             let target_addr = self.get_offset(relocation.offset);
-            // Only some relocations need the symbol
-            macro_rules! reloc_needs_symbol {
-                ($reloc:expr) => {{
-                    let symbol = if relocation.symbol != 0 {
-                        let Some(local_symbol) = self.find_local_symbol_by_index(relocation.symbol, true) else {
+            #[cfg(target_pointer_width = "64")]
+            trace!(target: &self.log_target, "Processing {relocation:?} at {:#018x}", target_addr);
+            #[cfg(not(target_pointer_width = "64"))]
+            trace!(target: &self.log_target, "Processing {relocation:?} at {:#010x}", target_addr);
+
+            // TP-relative relocations need the variable's offset in our TLS block rather than an address. TLS
+            // descriptors hold the same offset, returned by a resolver the code calls instead of loading it directly
+            if relocation.is_tls_tp_relative() || relocation.is_tls_descriptor() {
+                let Some(thread_pointer_offset) = self.tls_block.as_ref().map(tls::TlsBlock::thread_pointer_offset)
+                else {
+                    let description = self.describe_relocation_error(&relocation.to_error());
+                    error!(target: &self.log_target, "No TLS block for {description}");
+                    self.relocation_errors.push(relocation.to_error());
+                    continue;
+                };
+                let variable_offset = if relocation.symbol == STN_UNDEF as u32 {
+                    0
+                } else {
+                    match self.find_local_symbol_by_index(relocation.symbol, false) {
+                        Some(symbol) if symbol.shndx != SHN_UNDEF => symbol.value as i64,
+                        _ => {
+                            let description = self.describe_relocation_error(&relocation.to_error());
+                            error!(target: &self.log_target, "TLS variables from other modules are not supported, failed to handle {description}");
+                            self.relocation_errors.push(relocation.to_error());
                             continue;
-                        };
-                        if local_symbol.address.is_some() {
-                            Some(local_symbol)
-                        } else {
-                            let symbol_name = local_symbol.name.expect("Cannot lookup symbol without name");
-                            self.find_global_symbol(&symbol_name, true)
-                        }
-                    } else {
-                        None
-                    };
-                    match symbol {
-                        Some(s) => s.address.unwrap_or(self.get_offset(s.value as usize)),
-                        None => continue,
+                        },
+                    }
+                };
+                let value = variable_offset + relocation.addend + thread_pointer_offset;
+                if relocation.is_tls_descriptor() {
+                    let descriptor = target_addr as *mut usize;
+                    unsafe {
+                        *descriptor = tls::descriptor_resolver();
+                        *descriptor.add(1) = value as usize;
                     }
-                }};
+                } else {
+                    unsafe { RelocationValue::U64(value as u64).write(target_addr) };
+                }
+                continue;
+            }
+
+            // IFUNC resolvers pick an implementation at load time, the result is what goes in the slot
+            if relocation.is_irelative() {
+                if !self.call_ifunc_resolvers {
+                    debug!(target: &self.log_target, "Not calling IFUNC resolver for {relocation:?}");
+                    self.relocation_errors.push(relocation.to_error());
+                    continue;
+                }
+                let Some(resolver_addr) = add_addend(self.mapping.base, relocation.relative_addend(target_addr)) else {
+                    let description = self.describe_relocation_error(&relocation.to_overflow_error());
+                    error!(target: &self.log_target, "IFUNC resolver address for {description} overflows");
+                    self.relocation_errors.push(relocation.to_overflow_error());
+                    continue;
+                };
+                #[cfg(target_pointer_width = "64")]
+                trace!(target: &self.log_target, "Calling IFUNC resolver at {:#018x}", resolver_addr);
+                #[cfg(not(target_pointer_width = "64"))]
+                trace!(target: &self.log_target, "Calling IFUNC resolver at {:#010x}", resolver_addr);
+                let value = unsafe { call_ifunc_resolver(resolver_addr) };
+                unsafe { *(target_addr as *mut usize) = value };
+                continue;
             }
-            #[cfg(target_pointer_width = "64")]
-            trace!(target: &self.name, "Processing {relocation:?} at {:#018x}", target_addr);
-            #[cfg(not(target_pointer_width = "64"))]
-            trace!(target: &self.name, "Processing {relocation:?} at {:#010x}", target_addr);
 
-            #[cfg(target_arch = "x86_64")]
-            match relocation.rel_type {
-                elf::abi::R_X86_64_64 => {
-                    let symbol_addr = reloc_needs_symbol!("R_X86_64_64");
-                    unsafe { *(target_addr as *mut u64) = add_addend(symbol_addr, relocation.addend) as u64 };
-                },
-                elf::abi::R_X86_64_PC32 => {
-                    let symbol_addr = reloc_needs_symbol!("R_X86_64_PC32");
-                    unsafe {
-                        *(target_addr as *mut u32) = (add_addend(symbol_addr, relocation.addend) - target_addr) as u32
-                    };
-                },
-                elf::abi::R_X86_64_GLOB_DAT | elf::abi::R_X86_64_JUMP_SLOT => {
-                    let symbol_addr = reloc_needs_symbol!("R_X86_64_GLOB_DAT / R_X86_64_JUMP_SLOT");
-                    unsafe { *(target_addr as *mut u64) = symbol_addr as u64 };
-                },
-                elf::abi::R_X86_64_RELATIVE => {
-                    unsafe { *(target_addr as *mut u64) = add_addend(self.mapping.base, relocation.addend) as u64 };
-                },
-                elf::abi::R_X86_64_NONE | elf::abi::R_X86_64_COPY => {},
-                _ => {
-                    #[cfg(debug_assertions)]
-                    panic!(
-                        "Failed to handle relocation type {:#08x} for offset {:#012x}",
-                        relocation.rel_type, relocation.offset
-                    );
-                },
+            // The symbol is defined in this library, the data to copy into it comes from an override or a dependency
+            if relocation.is_copy() {
+                match self.copy_relocation_source(&relocation) {
+                    Some((source, size)) => unsafe {
+                        std::ptr::copy_nonoverlapping(source as *const u8, target_addr as *mut u8, size)
+                    },
+                    None => {
+                        let description = self.describe_relocation_error(&relocation.to_error());
+                        error!(target: &self.log_target, "No source for {description}");
+                        self.relocation_errors.push(relocation.to_error());
+                    },
+                }
+                continue;
             }
-            #[cfg(target_arch = "aarch64")]
-            match relocation.rel_type {
-                elf::abi::R_AARCH64_GLOB_DAT | elf::abi::R_AARCH64_JUMP_SLOT | elf::abi::R_AARCH64_ABS64 => {
-                    let symbol_addr = reloc_needs_symbol!("R_AARCH64_JUMP_SLOT");
-                    unsafe { *(target_addr as *mut u64) = add_addend(symbol_addr, relocation.addend) as u64 };
-                },
-                elf::abi::R_AARCH64_RELATIVE => {
-                    unsafe { *(target_addr as *mut u64) = add_addend(self.mapping.base, relocation.addend) as u64 };
-                },
-                _ => {
-                    #[cfg(debug_assertions)]
-                    panic!(
-                        "Failed to handle relocation type {:#08x} for offset {:#012x}",
-                        relocation.rel_type, relocation.offset
-                    );
+
+            // Only some relocations need the symbol
+            let symbol = if relocation.needs_symbol() {
+                if relocation.symbol == STN_UNDEF as u32 {
+                    // Nothing to resolve, make sure GOT slots don't keep whatever the file had in them
+                    #[cfg(target_pointer_width = "64")]
+                    warn!(target: &self.log_target, "Relocation type {:#x} at {:#018x} needs a symbol but has none", relocation.rel_type, relocation.offset);
+                    #[cfg(not(target_pointer_width = "64"))]
+                    warn!(target: &self.log_target, "Relocation type {:#x} at {:#010x} needs a symbol but has none", relocation.rel_type, relocation.offset);
+                    if relocation.is_got_slot() {
+                        unsafe { *(target_addr as *mut usize) = UNDEFINED_SYMBOL_VALUE };
+                    }
+                    continue;
+                }
+                let Some(local_symbol) = self.find_local_symbol_by_index(relocation.symbol, true) else {
+                    continue;
+                };
+                let weak_import = local_symbol.is_weak();
+                let symbol = if local_symbol.address.is_some() {
+                    Some(local_symbol)
+                } else {
+                    let symbol_name = local_symbol.name.expect("Cannot lookup symbol without name");
+                    match self.find_global_symbol(&symbol_name, true) {
+                        // An undefined weak reference is allowed, it resolves to 0 unless a default was provided
+                        None if weak_import => {
+                            let address = self.weak_defaults.get(&symbol_name).copied().unwrap_or(0);
+                            #[cfg(target_pointer_width = "64")]
+                            trace!(target: &self.log_target, r#"Resolved undefined weak "{symbol_name}" to {:#018x}"#, address);
+                            #[cfg(not(target_pointer_width = "64"))]
+                            trace!(target: &self.log_target, r#"Resolved undefined weak "{symbol_name}" to {:#010x}"#, address);
+                            Some(LinkingSymbol::from_address(Some(symbol_name), address))
+                        },
+                        symbol => symbol,
+                    }
+                };
+                let Some(symbol) = symbol else {
+                    continue;
+                };
+                Some(ResolvedSymbol {
+                    address: symbol.address.unwrap_or(self.get_offset(symbol.value as usize)),
+                    size: symbol.size,
+                })
+            } else {
+                None
+            };
+            let addend = if relocation.is_relative() {
+                relocation.relative_addend(target_addr)
+            } else {
+                relocation.addend
+            };
+            match apply_relocation(&relocation, addend, self.mapping.base, target_addr, symbol) {
+                Ok(value) => unsafe { value.write(target_addr) },
+                Err(error) => {
+                    let description = self.describe_relocation_error(&error);
+                    error!(target: &self.log_target, "Failed to handle {description}");
+                    self.relocation_errors.push(error);
                 },
             }
-            #[cfg(all(not(target_arch = "x86_64"), not(target_arch = "aarch64")))]
-            panic!("Unhandled system architecture")
+        }
+        if self.strict_relocations && !self.relocation_errors.is_empty() {
+            return Err(Error::UnsupportedRelocations(self.relocation_errors.clone()));
         }
 
         // Set up the PLT handler if needed
@@ -438,48 +1980,168 @@ impl JNI {
             let plt_2 = plt::trampoline as usize;
             #[cfg(target_pointer_width = "64")]
             {
-                debug!(target: &self.name, "Updating {} .got.plt entries at {:#018x}-{:#018x} using base address {:#018x}", got_entry_count, got_plt_addr, got_plt_addr + got_plt_header.sh_size as usize, self.get_offset(0));
-                debug!(target: &self.name, ".got.plt[0] {:#010x}", plt_0);
-                debug!(target: &self.name, ".got.plt[1] {:#018x}", plt_1);
-                debug!(target: &self.name, ".got.plt[2] {:#018x}", plt_2);
+                debug!(target: &self.log_target, "Updating {} .got.plt entries at {:#018x}-{:#018x} using base address {:#018x}", got_entry_count, got_plt_addr, got_plt_addr + got_plt_header.sh_size as usize, self.mapping.base.wrapping_sub(self.base_virtual_address));
+                debug!(target: &self.log_target, ".got.plt[0] {:#010x}", plt_0);
+                debug!(target: &self.log_target, ".got.plt[1] {:#018x}", plt_1);
+                debug!(target: &self.log_target, ".got.plt[2] {:#018x}", plt_2);
             }
             #[cfg(not(target_pointer_width = "64"))]
             {
-                debug!(target: &self.name, "Updating {} .got.plt entries at {:#010x}-{:#010x} using base address {:#010x}", got_entry_count, got_plt_addr, got_plt_addr + got_plt_header.sh_size as usize, self.get_offset(0));
-                debug!(target: &self.name, ".got.plt[0] {:#010x}", plt_0);
-                debug!(target: &self.name, ".got.plt[1] {:#010x}", plt_1);
-                debug!(target: &self.name, ".got.plt[2] {:#010x}", plt_2);
+                debug!(target: &self.log_target, "Updating {} .got.plt entries at {:#010x}-{:#010x} using base address {:#010x}", got_entry_count, got_plt_addr, got_plt_addr + got_plt_header.sh_size as usize, self.mapping.base.wrapping_sub(self.base_virtual_address));
+                debug!(target: &self.log_target, ".got.plt[0] {:#010x}", plt_0);
+                debug!(target: &self.log_target, ".got.plt[1] {:#010x}", plt_1);
+                debug!(target: &self.log_target, ".got.plt[2] {:#010x}", plt_2);
             }
             let got_plt_entries =
                 unsafe { std::slice::from_raw_parts_mut(got_plt_addr as *mut usize, got_entry_count) };
             got_plt_entries[0] = plt_0;
             got_plt_entries[1] = plt_1;
             got_plt_entries[2] = plt_2;
-            for entry in got_plt_entries[3..got_entry_count].iter_mut() {
-                *entry = self.get_offset(*entry);
+            // Only rebase the slots the trampoline is responsible for, jump slots from .rel.dyn/.rela.dyn may also
+            // point into .got.plt and have already been resolved
+            for relocation in lazy_relocations.iter() {
+                let entry = self.get_offset(relocation.offset) as *mut usize;
+                unsafe { *entry = self.get_offset(*entry) };
+                self.plt_stubs.push((entry as usize, unsafe { *entry }));
             }
         }
 
-        debug!(target: &self.name, "Initialized");
+        // Everything in .data.rel.ro and the GOT has been relocated by now so the RELRO region can be locked. Lazily
+        // bound slots are written by the PLT trampoline later, if any of them are inside the region (-z now without
+        // the linker dropping .got.plt) it has to stay writable
+        if let Some((relro_start, relro_end)) = self.mapping.relro_range() {
+            let lazy_slot_in_relro = lazy_relocations.iter().any(|r| {
+                let slot = self.get_offset(r.offset);
+                slot >= relro_start && slot < relro_end
+            });
+            if lazy_slot_in_relro {
+                debug!(target: &self.log_target, "Leaving RELRO writable as it contains lazily bound PLT entries");
+            } else {
+                self.mapping.protect_relro().map_err(Error::MemoryProtectFailed)?;
+            }
+        }
+
+        if self.mapping.detect_code_writes() {
+            self.mapping.protect_code().map_err(Error::MemoryProtectFailed)?;
+        }
+        if self.mapping.execute_on_demand() {
+            self.mapping.protect_code_until_run().map_err(Error::MemoryProtectFailed)?;
+        }
+
+        if self.call_constructors {
+            self.run_constructors();
+        }
+
+        debug!(target: &self.log_target, "Initialized");
+        debug::library_initialized(self.mapping.base as u64);
         Ok(())
     }
 
+    // DT_INIT then DT_INIT_ARRAY in order, matching the order ld.so runs them in
+    fn run_constructors(&mut self) {
+        let (init, init_array, _, _) = self.init_fini_functions();
+        let functions: Vec<_> = init.into_iter().chain(init_array).collect();
+        if !functions.is_empty() {
+            debug!(target: &self.log_target, "Running {} constructors", functions.len());
+        }
+        for function in functions {
+            Self::call_init_fini(function);
+        }
+    }
+
+    // DT_FINI_ARRAY in reverse then DT_FINI, the opposite of run_constructors
+    fn run_destructors(&mut self) {
+        let (_, _, fini_array, fini) = self.init_fini_functions();
+        let functions: Vec<_> = fini_array.into_iter().rev().chain(fini).collect();
+        if !functions.is_empty() {
+            debug!(target: &self.log_target, "Running {} destructors", functions.len());
+        }
+        for function in functions {
+            Self::call_init_fini(function);
+        }
+    }
+
+    fn call_init_fini(function: usize) {
+        // Called as init(argc, argv, envp), there are no arguments to give so pass an empty argv and envp
+        static EMPTY: [usize; 1] = [0];
+        let function: extern "C" fn(i32, *const usize, *const usize) = unsafe { std::mem::transmute(function) };
+        function(0, EMPTY.as_ptr(), EMPTY.as_ptr());
+    }
+
+    // Runtime addresses of DT_INIT, the DT_INIT_ARRAY entries, the DT_FINI_ARRAY entries and DT_FINI. The arrays are
+    // read from the mapping so must be relocated first, entries of 0 or -1 are placeholders and are skipped
+    fn init_fini_functions(&mut self) -> (Option<usize>, Vec<usize>, Vec<usize>, Option<usize>) {
+        let mut init = None;
+        let mut fini = None;
+        let mut init_array = (None, 0);
+        let mut fini_array = (None, 0);
+        if let Ok(Some(dynamic_section)) = self.elf_file.dynamic() {
+            for entry in dynamic_section {
+                match entry.d_tag {
+                    DT_INIT => init = Some(entry.d_ptr() as usize),
+                    DT_FINI => fini = Some(entry.d_ptr() as usize),
+                    DT_INIT_ARRAY => init_array.0 = Some(entry.d_ptr() as usize),
+                    DT_INIT_ARRAYSZ => init_array.1 = entry.d_val() as usize,
+                    DT_FINI_ARRAY => fini_array.0 = Some(entry.d_ptr() as usize),
+                    DT_FINI_ARRAYSZ => fini_array.1 = entry.d_val() as usize,
+                    _ => {},
+                }
+            }
+        }
+        let is_function = |address: &usize| *address != 0 && *address != usize::MAX;
+        let read_array = |(address, size): (Option<usize>, usize)| -> Vec<usize> {
+            let Some(start) = address.and_then(|address| self.checked_offset(address)) else {
+                return Vec::new();
+            };
+            let count = size / std::mem::size_of::<usize>();
+            let entries = unsafe { std::slice::from_raw_parts(start as *const usize, count) };
+            entries.iter().copied().filter(is_function).collect()
+        };
+        let init_array = read_array(init_array);
+        let fini_array = read_array(fini_array);
+        let init = init.filter(is_function).and_then(|address| self.checked_offset(address));
+        let fini = fini.filter(is_function).and_then(|address| self.checked_offset(address));
+        (init, init_array, fini_array, fini)
+    }
+
+    /// Writes made to executable pages since initialization, requires MappingOptions::with_code_write_detection
+    pub fn code_write_events(&self) -> Vec<CodeWriteEvent> {
+        codewrite::events_in(self.mapping.base, self.mapping.base + self.mapping.size)
+    }
+
+    /// Offsets from the mapping base of code pages that have run since initialization, requires
+    /// MappingOptions::with_execute_on_demand
+    pub fn executed_pages(&self) -> Vec<usize> {
+        let pages = codewrite::executed_pages_in(self.mapping.base, self.mapping.base + self.mapping.size);
+        pages.into_iter().map(|page| page - self.mapping.base).collect()
+    }
+
     // Look for a local symbol using its index
     fn find_local_symbol_by_index(&mut self, index: u32, include_overrides: bool) -> Option<LinkingSymbol> {
-        trace!(target: &self.name, "Looking for symbol {index}");
-        let (symbol_table, symbol_string_table) = self.elf_file.dynamic_symbol_table().ok()??;
+        trace!(target: &self.log_target, "Looking for symbol {index}");
+        let symbol_version = if include_overrides && !self.versioned_overrides.is_empty() {
+            self.required_symbol_version(index)
+        } else {
+            None
+        };
+        let (symbol_table, symbol_string_table) =
+            dynamic::symbol_table(&mut self.elf_file, self.dynamic_tables.as_ref())?;
         let symbol = symbol_table.get(index as usize).ok()?;
         let mut symbol_name = None;
         if symbol.st_name != 0 {
             let sym_name = symbol_string_table.get(symbol.st_name as usize).ok()?.to_owned();
-            trace!(target: &self.name, r#"Found name "{sym_name}" for index {index}"#);
+            trace!(target: &self.log_target, r#"Found name "{sym_name}" for index {index}"#);
             if include_overrides {
-                if let Some(&overridden_value) = self.symbol_overrides.get(&sym_name) {
+                let versioned_override = symbol_version
+                    .and_then(|version| self.versioned_overrides.get(&(sym_name.clone(), version)).copied());
+                if let Some(overridden_value) =
+                    versioned_override.or_else(|| self.symbol_overrides.get(&sym_name).copied())
+                {
                     let address = overridden_value.unwrap_or(UNDEFINED_SYMBOL_VALUE);
                     #[cfg(target_pointer_width = "64")]
-                    trace!(target: &self.name, r#"Found override {:#018x} for "{}""#, address, sym_name);
+                    trace!(target: &self.log_target, r#"Found override {:#018x} for "{}""#, address, sym_name);
                     #[cfg(not(target_pointer_width = "64"))]
-                    trace!(target: &self.name, r#"Found override {:#010x} for "{}""#, address, sym_name);
+                    trace!(target: &self.log_target, r#"Found override {:#010x} for "{}""#, address, sym_name);
                     return Some(LinkingSymbol::from_override(&symbol, Some(sym_name), address));
                 }
             }
@@ -488,60 +2150,83 @@ impl JNI {
         Some(LinkingSymbol::from(&symbol, symbol_name, self.mapping.base, self.base_virtual_address))
     }
 
+    // Address to copy a COPY relocation's data from and how many bytes, the size of this library's definition. An
+    // override takes precedence, otherwise the symbol is looked up in the dependencies, skipping this library
+    fn copy_relocation_source(&mut self, relocation: &Relocation) -> Option<(usize, usize)> {
+        let own_symbol = self.find_local_symbol_by_index(relocation.symbol, false)?;
+        let symbol_name = own_symbol.name.clone()?;
+        let size = own_symbol.size as usize;
+        let overridden = self.find_local_symbol_by_index(relocation.symbol, true)?;
+        if overridden.address != own_symbol.address {
+            trace!(target: &self.log_target, r#"Copying {size} bytes of "{symbol_name}" from its override"#);
+            return overridden.address.filter(|&address| address != UNDEFINED_SYMBOL_VALUE).map(|a| (a, size));
+        }
+        let source = self.find_global_symbol(&symbol_name, true)?.address?;
+        trace!(target: &self.log_target, r#"Copying {size} bytes of "{symbol_name}" from a dependency"#);
+        Some((source, size))
+    }
+
+    // The version an imported symbol is required to have, from .gnu.version and .gnu.version_r
+    fn required_symbol_version(&mut self, index: u32) -> Option<String> {
+        let version_table = self.elf_file.symbol_version_table().ok()??;
+        let requirement = version_table.get_requirement(index as usize).ok()??;
+        Some(requirement.name.to_owned())
+    }
+
     // Look for a local symbol using the hash tables
     fn find_local_symbol_by_name(&mut self, symbol_name: &str, include_overrides: bool) -> Option<LinkingSymbol> {
-        trace!(target: &self.name, r#"Looking for symbol "{symbol_name}" in hash tables"#);
-        // Check .gnu.hash first as it is faster
-        if let Ok(Some(&gnu_hash_section_header)) = self.elf_file.section_header_by_name(".gnu.hash") {
+        trace!(target: &self.log_target, r#"Looking for symbol "{symbol_name}" in hash tables"#);
+        // Check .gnu.hash first as it is faster. It only contains defined symbols so if it is present and doesn't
+        // have the name there's no point checking .hash. The bloom filter lets us skip the chain walk entirely for
+        // most names that aren't defined, which is the common case when searching through dependencies
+        if let Some(gnu_hash) = &self.gnu_hash {
+            if !gnu_hash.may_contain(symbol_name.as_bytes()) {
+                trace!(target: &self.log_target, r#"Rejected "{symbol_name}" using the .gnu.hash bloom filter"#);
+                return None;
+            }
             let elf_endianness = self.elf_file.ehdr.endianness;
             let elf_class = self.elf_file.ehdr.class;
-            // Only .debug sections can be compressed
-            let (gnu_hash_section, _) = self.elf_file.section_data(&gnu_hash_section_header).ok()?;
-            let gnu_hash_section = gnu_hash_section.to_vec(); // Can't have two references into self.elf_file so copy the section
-            let hash_section = GnuHashTable::new(elf_endianness, elf_class, &gnu_hash_section).ok()?;
-            let (symbol_table, symbol_string_table) = self.elf_file.dynamic_symbol_table().ok()??;
-            if let Some((_, symbol)) =
-                hash_section.find(symbol_name.as_bytes(), &symbol_table, &symbol_string_table).ok()?
-            {
-                trace!(target: &self.name, r#"Found "{symbol_name}" in .gnu.hash"#);
-                if include_overrides {
-                    if let Some(&overridden_value) = self.symbol_overrides.get(symbol_name) {
-                        let address = overridden_value.unwrap_or(UNDEFINED_SYMBOL_VALUE);
-                        #[cfg(target_pointer_width = "64")]
-                        trace!(target: &self.name, r#"Found override {:#018x} for "{}""#, address, symbol_name);
-                        #[cfg(not(target_pointer_width = "64"))]
-                        trace!(target: &self.name, r#"Found override {:#010x} for "{}""#, address, symbol_name);
-                        return Some(LinkingSymbol::from_override(&symbol, Some(symbol_name.to_owned()), address));
-                    }
+            let hash_section = GnuHashTable::new(elf_endianness, elf_class, gnu_hash.data()).ok()?;
+            let (symbol_table, symbol_string_table) =
+                dynamic::symbol_table(&mut self.elf_file, self.dynamic_tables.as_ref())?;
+            let (_, symbol) = hash_section.find(symbol_name.as_bytes(), &symbol_table, &symbol_string_table).ok()??;
+            trace!(target: &self.log_target, r#"Found "{symbol_name}" in .gnu.hash"#);
+            if include_overrides {
+                if let Some(&overridden_value) = self.symbol_overrides.get(symbol_name) {
+                    let address = overridden_value.unwrap_or(UNDEFINED_SYMBOL_VALUE);
+                    #[cfg(target_pointer_width = "64")]
+                    trace!(target: &self.log_target, r#"Found override {:#018x} for "{}""#, address, symbol_name);
+                    #[cfg(not(target_pointer_width = "64"))]
+                    trace!(target: &self.log_target, r#"Found override {:#010x} for "{}""#, address, symbol_name);
+                    return Some(LinkingSymbol::from_override(&symbol, Some(symbol_name.to_owned()), address));
                 }
-                return Some(LinkingSymbol::from(
-                    &symbol,
-                    Some(symbol_name.to_owned()),
-                    self.mapping.base,
-                    self.base_virtual_address,
-                ));
             }
+            return Some(LinkingSymbol::from(
+                &symbol,
+                Some(symbol_name.to_owned()),
+                self.mapping.base,
+                self.base_virtual_address,
+            ));
         }
 
         // Check .hash
-        if let Ok(Some(&sysv_hash_section_header)) = self.elf_file.section_header_by_name(".hash") {
+        if let Some(sysv_hash_section) = &self.sysv_hash {
             let elf_endianness = self.elf_file.ehdr.endianness;
             let elf_class = self.elf_file.ehdr.class;
-            let (sysv_hash_section, _) = self.elf_file.section_data(&sysv_hash_section_header).ok()?;
-            let sysv_hash_section = sysv_hash_section.to_vec();
-            let hash_section = SysVHashTable::new(elf_endianness, elf_class, &sysv_hash_section).ok()?;
-            let (symbol_table, symbol_string_table) = self.elf_file.dynamic_symbol_table().ok()??;
+            let hash_section = SysVHashTable::new(elf_endianness, elf_class, sysv_hash_section).ok()?;
+            let (symbol_table, symbol_string_table) =
+                dynamic::symbol_table(&mut self.elf_file, self.dynamic_tables.as_ref())?;
             if let Some((_, symbol)) =
                 hash_section.find(symbol_name.as_bytes(), &symbol_table, &symbol_string_table).ok()?
             {
-                trace!(target: &self.name, r#"Found "{symbol_name}" in .hash"#);
+                trace!(target: &self.log_target, r#"Found "{symbol_name}" in .hash"#);
                 if include_overrides {
                     if let Some(&overridden_value) = self.symbol_overrides.get(symbol_name) {
                         let address = overridden_value.unwrap_or(UNDEFINED_SYMBOL_VALUE);
                         #[cfg(target_pointer_width = "64")]
-                        trace!(target: &self.name, r#"Found override {:#018x} for "{}""#, address, symbol_name);
+                        trace!(target: &self.log_target, r#"Found override {:#018x} for "{}""#, address, symbol_name);
                         #[cfg(not(target_pointer_width = "64"))]
-                        trace!(target: &self.name, r#"Found override {:#010x} for "{}""#, address, symbol_name);
+                        trace!(target: &self.log_target, r#"Found override {:#010x} for "{}""#, address, symbol_name);
                         return Some(LinkingSymbol::from_override(&symbol, Some(symbol_name.to_owned()), address));
                     }
                 }
@@ -566,82 +2251,207 @@ impl JNI {
     //                              2-------3       JNI's DT_NEEDED
     //                            4---5     6       Each DT_NEEDED's dependencies
     //                                    7---8     And so on, descending the tree level by level
+    // The first strong definition wins. A weak definition is only used if no library defines a strong one, so a weak
+    // alias early in the search doesn't shadow the real definition later on
     fn find_global_symbol(&mut self, symbol_name: &str, include_overrides: bool) -> Option<LinkingSymbol> {
         if self.looking_for_symbol {
             return None;
         }
         self.looking_for_symbol = true;
-        trace!(target: &self.name, "Looking for symbol {symbol_name}");
-        for (_, dependency) in self.dependencies.iter() {
-            if let Some(dependency) = dependency {
+        trace!(target: &self.log_target, "Looking for symbol {symbol_name}");
+        let mut weak_symbol = None;
+        for preloaded in self.preloaded.iter() {
+            let mut preloaded = preloaded.lock().unwrap();
+            let symbol =
+                preloaded.find_local_symbol_by_name(symbol_name, include_overrides).filter(LinkingSymbol::is_exported);
+            match symbol {
+                Some(symbol) if !symbol.is_weak() => {
+                    trace!(target: &self.log_target, r#"Found "{symbol_name}" in preloaded library {}"#, preloaded.log_target);
+                    self.looking_for_symbol = false;
+                    return Some(symbol);
+                },
+                Some(symbol) => {
+                    trace!(target: &self.log_target, r#"Found weak "{symbol_name}" in preloaded library {}"#, preloaded.log_target);
+                    weak_symbol.get_or_insert(symbol);
+                },
+                None => {},
+            }
+        }
+        for index in 0..self.dependencies.len() {
+            if self.dependencies[index].1.is_none() {
+                self.load_deferred_dependency(index);
+            }
+            if let Some(dependency) = self.dependencies[index].1.clone() {
                 // looking_for_symbol protects us from recursively calling lock()
                 let mut dependency = dependency.lock().unwrap();
-                let symbol = dependency
+                // Hidden and internal symbols can't be bound to from outside the library that defines them
+                let local_symbol = dependency
                     .find_local_symbol_by_name(symbol_name, include_overrides)
-                    .or(dependency.find_global_symbol(symbol_name, include_overrides));
-                if symbol.is_some() {
-                    self.looking_for_symbol = false;
-                    return symbol;
+                    .filter(LinkingSymbol::is_exported);
+                // A deferred dependency isn't initialized with the rest, only once something actually binds to it
+                if local_symbol.is_some() && self.lazy_dependencies && !dependency.is_initialized() {
+                    debug!(target: &self.log_target, "Initializing dependency {} on first use", dependency.log_target);
+                    if let Err(error) = dependency.initialize() {
+                        error!(target: &self.log_target, "Failed to initialize dependency {} - {error}", dependency.log_target);
+                        // Binding to a library whose relocations weren't applied would crash on the first call, so
+                        // treat it like a deferred dependency that failed to load
+                        drop(dependency);
+                        let lib_name = self.dependencies[index].0.clone();
+                        self.dependencies[index].1 = None;
+                        self.dependency_errors.push((lib_name, error));
+                        continue;
+                    }
                 }
+                // A weak definition could still be overridden by a strong one further down this dependency's tree
+                let symbol = match local_symbol {
+                    Some(symbol) if !symbol.is_weak() => Some(symbol),
+                    local_symbol => match dependency.find_global_symbol(symbol_name, include_overrides) {
+                        Some(symbol) if !symbol.is_weak() => Some(symbol),
+                        global_symbol => local_symbol.or(global_symbol),
+                    },
+                };
+                match symbol {
+                    Some(symbol) if !symbol.is_weak() => {
+                        self.looking_for_symbol = false;
+                        return Some(symbol);
+                    },
+                    Some(symbol) => {
+                        trace!(target: &self.log_target, r#"Found weak "{symbol_name}" in {}"#, dependency.log_target);
+                        weak_symbol.get_or_insert(symbol);
+                    },
+                    None => {},
+                }
+            }
+        }
+        // Symbol providers and the host only fill in symbols the dependency graph doesn't define at all
+        if weak_symbol.is_some() {
+            trace!(target: &self.log_target, r#"No strong definition of "{symbol_name}", using the weak one"#);
+            self.looking_for_symbol = false;
+            return weak_symbol;
+        }
+        for (provider_name, provider) in self.symbol_providers.iter() {
+            if let Some(address) = provider.get(symbol_name) {
+                #[cfg(target_pointer_width = "64")]
+                trace!(target: &self.log_target, r#"Found "{symbol_name}" at {:#018x} in symbol provider {provider_name}"#, address);
+                #[cfg(not(target_pointer_width = "64"))]
+                trace!(target: &self.log_target, r#"Found "{symbol_name}" at {:#010x} in symbol provider {provider_name}"#, address);
+                self.looking_for_symbol = false;
+                return Some(LinkingSymbol::from_address(Some(symbol_name.to_owned()), address));
+            }
+        }
+        if self.host_fallback {
+            if let Some(address) = host::default_symbol(symbol_name) {
+                #[cfg(target_pointer_width = "64")]
+                trace!(target: &self.log_target, r#"Found "{symbol_name}" at {:#018x} in the host process"#, address);
+                #[cfg(not(target_pointer_width = "64"))]
+                trace!(target: &self.log_target, r#"Found "{symbol_name}" at {:#010x} in the host process"#, address);
+                self.looking_for_symbol = false;
+                return Some(LinkingSymbol::from_address(Some(symbol_name.to_owned()), address));
             }
         }
         self.looking_for_symbol = false;
         None
     }
 
-    #[cfg(feature = "inline-asm")]
-    pub(crate) fn plt_callback(&mut self, reloc_index: usize) -> Option<usize> {
-        let mut relocation_offset = None;
-        let mut relocation_addend = None;
-        let mut relocation_symbol = None;
-
-        if let Ok(Some(&rel_plt_header)) = self.elf_file.section_header_by_name(".rel.plt") {
-            debug!(target: &self.name, "Trying to resolve .rel.plt[{}]", reloc_index);
-            if let Ok(mut rel_plt) = self.elf_file.section_data_as_rels(&rel_plt_header) {
-                if let Some(reloc) = rel_plt.nth(reloc_index) {
-                    relocation_offset = Some(reloc.r_offset as usize);
-                    relocation_addend = Some(0);
-                    relocation_symbol = Some(reloc.r_sym);
-                }
-            }
+    /// Tear the library down now instead of when it's dropped. JNI_OnUnload and registered destructors are run, the
+    /// library is removed from the debug registry, dependencies are released and the memory is unmapped. It can't be
+    /// used afterwards, symbol lookups fail and initialize() returns Error::Unloaded. Calling it again does nothing
+    pub fn unload(&mut self) {
+        if self.unloaded {
+            return;
         }
-        if let Ok(Some(&rela_plt_header)) = self.elf_file.section_header_by_name(".rela.plt") {
-            debug!(target: &self.name, "Trying to resolve .rela.plt[{}]", reloc_index);
-            if let Ok(mut rela_plt) = self.elf_file.section_data_as_relas(&rela_plt_header) {
-                if let Some(reloc) = rela_plt.nth(reloc_index) {
-                    relocation_offset = Some(reloc.r_offset as usize);
-                    relocation_addend = Some(reloc.r_addend);
-                    relocation_symbol = Some(reloc.r_sym);
-                }
+        debug!(target: &self.log_target, "Unloading");
+        if let Some(vm) = self.java_vm {
+            self.call_jni_onunload(vm as *mut c_void);
+        }
+        // Only after JNI_OnUnload as looking it up fails once unloaded
+        self.unloaded = true;
+        if self.have_been_initialized {
+            debug!(target: &self.log_target, "Running registered destructors");
+            atexit::run(self.mapping.base, self.mapping.base + self.mapping.size);
+            if self.call_constructors {
+                self.run_destructors();
             }
         }
+        debug::remove_library(self.mapping.base as u64);
+        self.flat_namespace = None;
+        self.dependencies.clear();
+        self.deferred_dependencies.clear();
+        self.preloaded.clear();
+        #[cfg(feature = "inline-asm")]
+        self.dlopen_dependencies.clear();
+        self.tls_block = None;
+        self.mapping.unmap();
+    }
+
+    pub fn is_unloaded(&self) -> bool {
+        self.unloaded
+    }
+
+    #[cfg(feature = "inline-asm")]
+    pub(crate) fn plt_callback(&mut self, reloc_index: usize) -> Option<usize> {
+        if self.plt_relocations.is_none() {
+            let plt_relocations =
+                self.relocation_table(".rela.plt").or_else(|| self.relocation_table(".rel.plt")).unwrap_or_default();
+            debug!(target: &self.log_target, "Cached {} PLT relocations", plt_relocations.len());
+            self.plt_relocations = Some(plt_relocations);
+        }
 
-        let relocation_offset = relocation_offset?;
-        let relocation_addend = relocation_addend?;
-        let relocation_symbol = relocation_symbol?;
-        debug!(target: &self.name, "PLT relocation {reloc_index} is for symbol {relocation_symbol}");
+        debug!(target: &self.log_target, "Trying to resolve PLT relocation {}", reloc_index);
+        let relocation = self.plt_relocations.as_ref()?.get(reloc_index)?.clone();
+        let relocation_symbol = relocation.symbol;
+        debug!(target: &self.log_target, "PLT relocation {reloc_index} is for symbol {relocation_symbol}");
         let symbol_addr = match self.resolve_plt_symbol(relocation_symbol) {
             Some(symbol) => Some(symbol),
             None => {
-                error!(target: &self.name, "Failed to resolve PLT symbol {relocation_symbol}. We are probably about to crash");
+                error!(target: &self.log_target, "Failed to resolve PLT symbol {relocation_symbol}. We are probably about to crash");
                 None
             },
         }?;
-        let target_addr = self.get_offset(relocation_offset);
-        let target_value = add_addend(symbol_addr, relocation_addend);
+        // While tracing the slot is left pointing at the PLT stub so every call comes back through here
+        if let Some(import_trace) = self.import_trace.clone() {
+            let symbol_name = self.find_local_symbol_by_index(relocation_symbol, false).and_then(|s| s.name);
+            let symbol_name = symbol_name.unwrap_or_else(|| format!("#{relocation_symbol}"));
+            trace!(target: &self.log_target, "Traced call to {symbol_name}");
+            *import_trace.lock().unwrap().entry(symbol_name).or_insert(0) += 1;
+            return Some(symbol_addr);
+        }
+        let target_addr = self.get_offset(relocation.offset);
+        // Must match what initialize() would have written if the slot was resolved eagerly
+        let target_value = relocation.jump_slot_value(symbol_addr)?;
         #[cfg(target_pointer_width = "64")]
-        debug!(target: &self.name, "Handling PLT entry {reloc_index} by writing {:#018x} to {:#018x}", target_value, target_addr);
+        debug!(target: &self.log_target, "Handling PLT entry {reloc_index} by writing {:#018x} to {:#018x}", target_value, target_addr);
         #[cfg(not(target_pointer_width = "64"))]
-        debug!(target: &self.name, "Handling PLT entry {reloc_index} by writing {:#010x} to {:#010x}", target_value, target_addr);
+        debug!(target: &self.log_target, "Handling PLT entry {reloc_index} by writing {:#010x} to {:#010x}", target_value, target_addr);
         unsafe { *(target_addr as *mut usize) = target_value }
         Some(symbol_addr)
     }
 
+    /// Count calls the library makes to imported functions until stop_tracing_imports() is called. Lazily bound PLT
+    /// slots are reset so every call goes through the PLT trampoline instead of only the first. Only calls routed
+    /// through the PLT are seen, jump slots bound at load time (e.g. -z now), calls through the GOT and calls within
+    /// the library aren't. Each traced call pays for a symbol lookup
+    #[cfg(feature = "inline-asm")]
+    pub fn trace_imports(&mut self) -> ImportTracer {
+        let import_trace = self.import_trace.get_or_insert_with(Default::default).clone();
+        debug!(target: &self.log_target, "Tracing calls through {} PLT slots", self.plt_stubs.len());
+        for &(slot, stub) in self.plt_stubs.iter() {
+            unsafe { *(slot as *mut usize) = stub };
+        }
+        ImportTracer::new(import_trace)
+    }
+
+    /// Stop counting calls to imported functions. Each slot is bound to its symbol again on its next call
+    #[cfg(feature = "inline-asm")]
+    pub fn stop_tracing_imports(&mut self) {
+        self.import_trace = None;
+    }
+
     #[cfg(feature = "inline-asm")]
     fn resolve_plt_symbol(&mut self, symbol_idx: u32) -> Option<usize> {
         let local_symbol = self.find_local_symbol_by_index(symbol_idx, true)?;
         if let Some(ref local_symbol_name) = local_symbol.name {
-            debug!(target: &self.name, "Found name '{local_symbol_name}' for PLT symbol {symbol_idx}");
+            debug!(target: &self.log_target, "Found name '{local_symbol_name}' for PLT symbol {symbol_idx}");
         }
         if local_symbol.address.is_some() {
             return local_symbol.address;
@@ -649,8 +2459,13 @@ impl JNI {
         if local_symbol.value != 0 {
             return Some(self.get_offset(local_symbol.value as usize));
         }
+        let weak_import = local_symbol.is_weak();
         let symbol_name = local_symbol.name?;
-        let global_symbol = self.find_global_symbol(&symbol_name, true)?;
+        let global_symbol = match self.find_global_symbol(&symbol_name, true) {
+            Some(global_symbol) => global_symbol,
+            None if weak_import => return self.weak_defaults.get(&symbol_name).copied(),
+            None => return None,
+        };
         if global_symbol.address.is_some() {
             return global_symbol.address;
         }
@@ -663,19 +2478,140 @@ impl JNI {
 
 impl Drop for JNI {
     fn drop(&mut self) {
-        debug::remove_library(self.mapping.base as u64);
+        self.unload();
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelocationError {
+    pub rel_type: u32,
+    pub offset: usize, // Virtual address in the file, not in the mapping
+    pub symbol: u32,   // Index into the dynamic symbol table
+    pub kind: RelocationErrorKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelocationErrorKind {
+    Unsupported, // Unknown type, or a missing symbol or TLS block it needs
+    Overflow,    // The value doesn't fit, e.g. a PC-relative displacement out of 32 bit range
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TlsInfo {
+    pub template_size: usize, // Size of the initialization image (p_filesz)
+    pub total_size: usize,    // Size of the whole block including zero-initialized data (p_memsz)
+    pub alignment: usize,     // Required alignment of the block (p_align)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoadedLib {
+    pub name: String,
+    pub path: PathBuf,
+    pub base: usize, // Start of the mapping
+    pub size: usize, // Size of the whole mapping, including gaps between segments
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InertReport {
+    pub soname: Option<String>,
+    pub needed: Vec<String>,               // DT_NEEDED entries
+    pub dependencies: Vec<(String, bool)>, // Each dependency and whether it was found
+    pub relocation_count: usize,
+    pub relocation_errors: Vec<RelocationError>,
+    pub imported_symbols: Vec<String>,
+    pub layout: Layout,
+    pub tls: Option<TlsInfo>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelocationSummary {
+    pub sections: Vec<(String, usize)>, // Each relocation section present and how many entries it has
+    pub types: Vec<(u32, usize)>,       // Each relocation type used and how often, .relr.dyn isn't included
+    pub unsupported_types: Vec<u32>,    // Types initialize() can't apply on this architecture
+}
+
+impl RelocationSummary {
+    /// Whether initialize() can apply every relocation. .relr.dyn isn't supported at all
+    pub fn fully_supported(&self) -> bool {
+        self.unsupported_types.is_empty()
+            && !self.sections.iter().any(|(name, entries)| name == ".relr.dyn" && *entries > 0)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompatibilityReport {
+    pub architecture_matches: bool, // Machine and class match the host
+    pub relocations: RelocationSummary,
+    pub tls: Option<TlsInfo>, // Only the initial-exec model is supported, on a single thread
+    pub uses_ifunc: bool,     // Has IRELATIVE relocations
+    pub uses_relr: bool,      // Has a non-empty .relr.dyn
+    pub required_versions: Vec<(String, String)>, // Dependency and version name from .gnu.version_r
+    pub lazy_binding: bool,   // PLT slots are bound on first call rather than by initialize()
+    pub unsupported: Vec<(String, String)>, // Each feature this build can't handle and why
+}
+
+impl CompatibilityReport {
+    /// Whether nothing the library uses is unsupported
+    pub fn fully_supported(&self) -> bool {
+        self.unsupported.is_empty()
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DependencyEvent {
+    pub name: String, // DT_NEEDED entry
+    pub source: DependencySource,
+    pub path: Option<PathBuf>, // Where the library was loaded from, if anywhere
+    pub loaded: bool,          // False if it couldn't be found, or was found but failed to load
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DependencySource {
+    Added,                  // Added before load_dependencies() with add_dependency() or similar
+    SymbolProvider,         // Handled by a symbol table or host library
+    Search(SearchLocation), // Found by searching
+    NotFound,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Layout {
+    pub segments: Vec<SegmentLayout>, // PT_LOAD segments in file order
+    pub sections: Vec<SectionLayout>, // Only the sections present in the file
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentLayout {
+    pub start: usize,
+    pub end: usize, // Exclusive, includes zero-initialized data
+    pub readable: bool,
+    pub writable: bool,
+    pub executable: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SectionLayout {
+    pub name: String,
+    pub start: usize,
+    pub end: usize, // Exclusive
+}
+
+#[derive(Clone)]
 struct Relocation {
     offset: usize,
     rel_type: u32,
     symbol: u32,
     addend: i64,
+    implicit_addend: bool, // REL relocations store the addend at the target instead
 }
 impl From<Rel> for Relocation {
     fn from(relocation: Rel) -> Self {
-        Self { offset: relocation.r_offset as usize, rel_type: relocation.r_type, symbol: relocation.r_sym, addend: 0 }
+        Self {
+            offset: relocation.r_offset as usize,
+            rel_type: relocation.r_type,
+            symbol: relocation.r_sym,
+            addend: 0,
+            implicit_addend: true,
+        }
     }
 }
 impl From<Rela> for Relocation {
@@ -685,8 +2621,172 @@ impl From<Rela> for Relocation {
             rel_type: relocation.r_type,
             symbol: relocation.r_sym,
             addend: relocation.r_addend,
+            implicit_addend: false,
+        }
+    }
+}
+impl Relocation {
+    // Jump slots are the only relocations that can be resolved lazily by the PLT trampoline
+    fn is_jump_slot(&self) -> bool {
+        #[cfg(target_arch = "x86_64")]
+        let jump_slot = elf::abi::R_X86_64_JUMP_SLOT;
+        #[cfg(target_arch = "aarch64")]
+        let jump_slot = elf::abi::R_AARCH64_JUMP_SLOT;
+        #[cfg(all(not(target_arch = "x86_64"), not(target_arch = "aarch64")))]
+        let jump_slot = u32::MAX;
+        self.rel_type == jump_slot
+    }
+
+    // The addend of a REL relative relocation is whatever the linker left at the target
+    fn relative_addend(&self, target_addr: usize) -> i64 {
+        if self.implicit_addend {
+            unsafe { *(target_addr as *const isize) as i64 }
+        } else {
+            self.addend
+        }
+    }
+
+    // Relocations that can't be applied without resolving the symbol they refer to
+    fn needs_symbol(&self) -> bool {
+        #[cfg(target_arch = "x86_64")]
+        let needs_symbol = matches!(
+            self.rel_type,
+            elf::abi::R_X86_64_64
+                | elf::abi::R_X86_64_PC32
+                | elf::abi::R_X86_64_GLOB_DAT
+                | elf::abi::R_X86_64_JUMP_SLOT
+                | elf::abi::R_X86_64_SIZE32
+                | elf::abi::R_X86_64_SIZE64
+        );
+        #[cfg(target_arch = "aarch64")]
+        let needs_symbol = matches!(
+            self.rel_type,
+            elf::abi::R_AARCH64_GLOB_DAT | elf::abi::R_AARCH64_JUMP_SLOT | elf::abi::R_AARCH64_ABS64
+        );
+        #[cfg(all(not(target_arch = "x86_64"), not(target_arch = "aarch64")))]
+        let needs_symbol = false;
+        needs_symbol
+    }
+
+    // Relocations that fill in a GOT entry with a symbol's address
+    fn is_got_slot(&self) -> bool {
+        #[cfg(target_arch = "x86_64")]
+        let glob_dat = elf::abi::R_X86_64_GLOB_DAT;
+        #[cfg(target_arch = "aarch64")]
+        let glob_dat = elf::abi::R_AARCH64_GLOB_DAT;
+        #[cfg(all(not(target_arch = "x86_64"), not(target_arch = "aarch64")))]
+        let glob_dat = u32::MAX;
+        self.rel_type == glob_dat || self.is_jump_slot()
+    }
+
+    // Initial-exec TLS relocations, general-dynamic ones that go through __tls_get_addr aren't supported
+    fn is_tls_tp_relative(&self) -> bool {
+        #[cfg(target_arch = "x86_64")]
+        let tp_relative = elf::abi::R_X86_64_TPOFF64;
+        #[cfg(target_arch = "aarch64")]
+        let tp_relative = elf::abi::R_AARCH64_TLS_TPREL;
+        #[cfg(all(not(target_arch = "x86_64"), not(target_arch = "aarch64")))]
+        let tp_relative = u32::MAX;
+        self.rel_type == tp_relative
+    }
+
+    // Two word TLS descriptors, a resolver and its argument. Only the static form is supported. The code sequences
+    // that load them (e.g. R_X86_64_GOTPC32_TLSDESC) are resolved by the static linker and never reach us
+    fn is_tls_descriptor(&self) -> bool {
+        #[cfg(target_arch = "x86_64")]
+        let tls_descriptor = elf::abi::R_X86_64_TLSDESC;
+        #[cfg(target_arch = "aarch64")]
+        let tls_descriptor = elf::abi::R_AARCH64_TLSDESC;
+        #[cfg(all(not(target_arch = "x86_64"), not(target_arch = "aarch64")))]
+        let tls_descriptor = u32::MAX;
+        self.rel_type == tls_descriptor
+    }
+
+    // Copies a data object's initial contents into this library's own definition of it
+    fn is_copy(&self) -> bool {
+        #[cfg(target_arch = "x86_64")]
+        let copy = elf::abi::R_X86_64_COPY;
+        #[cfg(target_arch = "aarch64")]
+        let copy = elf::abi::R_AARCH64_COPY;
+        #[cfg(all(not(target_arch = "x86_64"), not(target_arch = "aarch64")))]
+        let copy = u32::MAX;
+        self.rel_type == copy
+    }
+
+    fn is_irelative(&self) -> bool {
+        #[cfg(target_arch = "x86_64")]
+        let irelative = elf::abi::R_X86_64_IRELATIVE;
+        #[cfg(target_arch = "aarch64")]
+        let irelative = elf::abi::R_AARCH64_IRELATIVE;
+        #[cfg(all(not(target_arch = "x86_64"), not(target_arch = "aarch64")))]
+        let irelative = u32::MAX;
+        self.rel_type == irelative
+    }
+
+    // Whether initialize() knows how to apply this type of relocation
+    fn is_supported(&self) -> bool {
+        if self.is_relative()
+            || self.is_irelative()
+            || self.is_copy()
+            || self.is_tls_tp_relative()
+            || self.is_tls_descriptor()
+        {
+            return true;
+        }
+        #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+        let supported = {
+            let symbol = ResolvedSymbol { address: 0, size: 0 };
+            apply_relocation(self, 0, 0, 0, Some(symbol)).is_ok()
+        };
+        #[cfg(all(not(target_arch = "x86_64"), not(target_arch = "aarch64")))]
+        let supported = self.rel_type == R_NONE;
+        supported
+    }
+
+    // Order relocations are applied in by initialize(), lowest first
+    fn phase(&self) -> u8 {
+        if self.is_relative() {
+            0
+        } else if self.is_irelative() {
+            3
+        } else if self.is_tls_descriptor() {
+            2
+        } else {
+            1
+        }
+    }
+
+    fn is_relative(&self) -> bool {
+        #[cfg(target_arch = "x86_64")]
+        let relative = elf::abi::R_X86_64_RELATIVE;
+        #[cfg(target_arch = "aarch64")]
+        let relative = elf::abi::R_AARCH64_RELATIVE;
+        #[cfg(all(not(target_arch = "x86_64"), not(target_arch = "aarch64")))]
+        let relative = GENERIC_RELATIVE;
+        self.rel_type == relative
+    }
+
+    fn to_error(&self) -> RelocationError {
+        RelocationError {
+            rel_type: self.rel_type,
+            offset: self.offset,
+            symbol: self.symbol,
+            kind: RelocationErrorKind::Unsupported,
         }
     }
+
+    fn to_overflow_error(&self) -> RelocationError {
+        RelocationError { kind: RelocationErrorKind::Overflow, ..self.to_error() }
+    }
+
+    // x86_64 jump slots are just the symbol address and any addend is ignored, aarch64 uses the symbol plus addend
+    fn jump_slot_value(&self, symbol_addr: usize) -> Option<usize> {
+        #[cfg(target_arch = "x86_64")]
+        let value = Some(symbol_addr);
+        #[cfg(not(target_arch = "x86_64"))]
+        let value = add_addend(symbol_addr, self.addend);
+        value
+    }
 }
 impl Debug for Relocation {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -706,6 +2806,21 @@ impl Debug for Relocation {
     }
 }
 
+// A dependency that only provides symbol addresses, without a mapped library behind it
+enum SymbolProvider {
+    Table(HashMap<String, usize>),
+    Host(host::HostLibrary),
+}
+
+impl SymbolProvider {
+    fn get(&self, symbol_name: &str) -> Option<usize> {
+        match self {
+            SymbolProvider::Table(symbols) => symbols.get(symbol_name).copied(),
+            SymbolProvider::Host(host_library) => host_library.symbol(symbol_name),
+        }
+    }
+}
+
 // Used to represent a symbol while linking
 #[allow(dead_code)]
 struct LinkingSymbol {
@@ -748,13 +2863,206 @@ impl LinkingSymbol {
             visibility: symbol.st_vis(),
         }
     }
+
+    // Used for symbols that don't come from an ELF file, like symbol providers
+    pub fn from_address(name: Option<String>, address: usize) -> Self {
+        LinkingSymbol {
+            name,
+            shndx: SHN_UNDEF,
+            value: 0,
+            address: Some(address),
+            size: 0,
+            sym_type: STT_NOTYPE,
+            binding: STB_GLOBAL,
+            visibility: STV_DEFAULT,
+        }
+    }
+
+    pub fn is_exported(&self) -> bool {
+        matches!(self.visibility, STV_DEFAULT | STV_PROTECTED)
+    }
+
+    pub fn is_weak(&self) -> bool {
+        self.binding == STB_WEAK
+    }
+}
+
+// Whether a dependency registered under `key` satisfies a request for `name`, either by the name it was added with or
+// by its soname. A dependency that is locked elsewhere can only be matched by name
+fn dependency_matches(name: &str, key: &str, lib: &Option<Arc<Mutex<Box<JNI>>>>) -> bool {
+    key == name || lib.as_ref().is_some_and(|lib| lib.try_lock().is_ok_and(|lib| lib.soname() == Some(name)))
+}
+
+// The string values from the .dynamic section that are needed for linking
+struct DynamicStrings {
+    needed: Vec<String>,
+    runpath: Option<PathBuf>,
+    soname: Option<String>,
+    origin_required: bool, // DF_ORIGIN in DT_FLAGS or DF_1_ORIGIN in DT_FLAGS_1
+}
+
+// Whether a search path refers to the directory of the library that needs it
+fn has_origin_token(path: &str) -> bool {
+    path.contains("$ORIGIN") || path.contains("${ORIGIN}")
+}
+
+// Read the DT_NEEDED entries, DT_RUNPATH and DT_SONAME from the .dynamic section, and whether $ORIGIN must resolve
+fn read_dynamic_strings<S: Read + Seek>(elf_file: &mut ElfStream<AnyEndian, S>) -> Result<DynamicStrings, Error> {
+    // Dependencies are stored using DT_NEEDED keys in the .dynamic section. We also need DT_RUNPATH for locating
+    let Ok(Some(dynamic_section)) = elf_file.dynamic() else {
+        return Err(Error::NoDynamicSection);
+    };
+    let mut dependency_offsets = Vec::new();
+    let mut dt_runpath_offset = None;
+    let mut dt_soname_offset = None;
+    let mut origin_required = false;
+    for entry in dynamic_section {
+        match entry.d_tag {
+            DT_NEEDED => {
+                dependency_offsets.push(entry.d_val() as usize);
+            },
+            DT_RUNPATH => {
+                dt_runpath_offset = Some(entry.d_val() as usize);
+            },
+            DT_SONAME => {
+                dt_soname_offset = Some(entry.d_val() as usize);
+            },
+            DT_FLAGS => {
+                origin_required |= entry.d_val() & DF_ORIGIN as u64 != 0;
+            },
+            DT_FLAGS_1 => {
+                origin_required |= entry.d_val() & DF_1_ORIGIN as u64 != 0;
+            },
+            _ => {},
+        }
+    }
+
+    // Values from .dynamic are offsets into the .dynstr string table
+    let dynamic_tables = dynamic::DynamicTables::read(elf_file)?;
+    let dynamic_string_table = if let Some(dynamic_tables) = &dynamic_tables {
+        dynamic_tables.string_table()
+    } else {
+        let Ok(Some(dynamic_string_table_header)) = elf_file.section_header_by_name(".dynstr") else {
+            return Err(Error::NoDynstr);
+        };
+        let dynamic_string_table_header = *dynamic_string_table_header; // End mutable borrow of elf_file
+        let Ok(dynamic_string_table) = elf_file.section_data_as_strtab(&dynamic_string_table_header) else {
+            return Err(Error::DynstrParseFailed);
+        };
+        dynamic_string_table
+    };
+    let dependencies: Vec<String> = dependency_offsets
+        .into_iter()
+        .flat_map(|offset| dynamic_string_table.get(offset))
+        .map(|s| s.to_string())
+        .collect();
+    let dt_runpath = dt_runpath_offset.and_then(|offset| dynamic_string_table.get(offset).ok()).map(PathBuf::from);
+    let soname = dt_soname_offset.and_then(|offset| dynamic_string_table.get(offset).ok()).map(|s| s.to_string());
+    Ok(DynamicStrings { needed: dependencies, runpath: dt_runpath, soname, origin_required })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ResolvedSymbol {
+    address: usize,
+    size: u64,
+}
+
+// What a relocation writes to its target
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RelocationValue {
+    Nothing,
+    U32(u32),
+    U64(u64),
+}
+
+impl RelocationValue {
+    #[cfg(all(not(target_arch = "x86_64"), not(target_arch = "aarch64")))]
+    fn pointer(value: usize) -> Self {
+        #[cfg(target_pointer_width = "64")]
+        let value = RelocationValue::U64(value as u64);
+        #[cfg(not(target_pointer_width = "64"))]
+        let value = RelocationValue::U32(value as u32);
+        value
+    }
+
+    unsafe fn write(self, target_addr: usize) {
+        match self {
+            RelocationValue::Nothing => {},
+            RelocationValue::U32(value) => *(target_addr as *mut u32) = value,
+            RelocationValue::U64(value) => *(target_addr as *mut u64) = value,
+        }
+    }
+}
+
+// Work out the value a relocation should write without touching memory. The addend is passed separately as REL
+// relocations read it from the target. Relocations that need a symbol fail without one
+#[cfg_attr(not(target_arch = "x86_64"), allow(unused_variables))]
+fn apply_relocation(
+    relocation: &Relocation, addend: i64, mapping_base: usize, target_addr: usize, symbol: Option<ResolvedSymbol>,
+) -> Result<RelocationValue, RelocationError> {
+    let symbol = || symbol.ok_or_else(|| relocation.to_error());
+    let plus_addend = |addr: usize| add_addend(addr, addend).ok_or_else(|| relocation.to_overflow_error());
+    #[cfg(target_arch = "x86_64")]
+    let value = match relocation.rel_type {
+        elf::abi::R_X86_64_64 => RelocationValue::U64(plus_addend(symbol()?.address)? as u64),
+        elf::abi::R_X86_64_PC32 => {
+            // The linker would reject a displacement that doesn't fit, so rather than truncate it report an error
+            let displacement = plus_addend(symbol()?.address)? as i128 - target_addr as i128;
+            let displacement = i32::try_from(displacement).map_err(|_| relocation.to_overflow_error())?;
+            RelocationValue::U32(displacement as u32)
+        },
+        elf::abi::R_X86_64_GLOB_DAT | elf::abi::R_X86_64_JUMP_SLOT => RelocationValue::U64(symbol()?.address as u64),
+        elf::abi::R_X86_64_RELATIVE => RelocationValue::U64(plus_addend(mapping_base)? as u64),
+        elf::abi::R_X86_64_SIZE32 => {
+            let size = u32::try_from(plus_addend(symbol()?.size as usize)?);
+            RelocationValue::U32(size.map_err(|_| relocation.to_overflow_error())?)
+        },
+        elf::abi::R_X86_64_SIZE64 => RelocationValue::U64(plus_addend(symbol()?.size as usize)? as u64),
+        elf::abi::R_X86_64_NONE => RelocationValue::Nothing,
+        _ => return Err(relocation.to_error()),
+    };
+    #[cfg(target_arch = "aarch64")]
+    let value = match relocation.rel_type {
+        elf::abi::R_AARCH64_GLOB_DAT | elf::abi::R_AARCH64_JUMP_SLOT | elf::abi::R_AARCH64_ABS64 => {
+            RelocationValue::U64(plus_addend(symbol()?.address)? as u64)
+        },
+        elf::abi::R_AARCH64_RELATIVE => RelocationValue::U64(plus_addend(mapping_base)? as u64),
+        R_NONE => RelocationValue::Nothing,
+        _ => return Err(relocation.to_error()),
+    };
+    // Without a backend for this architecture only the relocations that don't need to know anything about it work
+    #[cfg(all(not(target_arch = "x86_64"), not(target_arch = "aarch64")))]
+    let value = match relocation.rel_type {
+        R_NONE => RelocationValue::Nothing,
+        GENERIC_RELATIVE => RelocationValue::pointer(plus_addend(mapping_base)?),
+        _ => return Err(relocation.to_error()),
+    };
+    Ok(value)
+}
+
+// Call an IFUNC resolver and return the implementation it picked. aarch64 resolvers are passed AT_HWCAP like glibc
+// does, without _IFUNC_ARG_HWCAP so they don't look at the second argument
+unsafe fn call_ifunc_resolver(resolver_addr: usize) -> usize {
+    #[cfg(target_arch = "aarch64")]
+    let value = {
+        let resolver: extern "C" fn(u64, *const c_void) -> usize = std::mem::transmute(resolver_addr);
+        resolver(nix::libc::getauxval(nix::libc::AT_HWCAP), std::ptr::null())
+    };
+    #[cfg(not(target_arch = "aarch64"))]
+    let value = {
+        let resolver: extern "C" fn() -> usize = std::mem::transmute(resolver_addr);
+        resolver()
+    };
+    value
 }
 
-fn add_addend(addr: usize, addend: i64) -> usize {
+// None if the result doesn't fit in an address, rather than silently wrapping around
+fn add_addend(addr: usize, addend: i64) -> Option<usize> {
+    let magnitude = usize::try_from(addend.unsigned_abs()).ok()?;
     if addend.is_negative() {
-        addr - (addend.unsigned_abs() as usize)
+        addr.checked_sub(magnitude)
     } else {
-        addr + (addend as usize)
+        addr.checked_add(magnitude)
     }
 }
 
@@ -766,12 +3074,39 @@ pub enum Error {
     FailedToOpen,
     #[error("the file is not a shared object file")]
     NotDynamicObject,
+    #[error("failed to get the page size - {0}")]
+    PageSize(String),
     #[error("failed to map memory - {0}")]
     MemoryMapFailed(String),
+    #[error("failed to change memory protection - {0}")]
+    MemoryProtectFailed(String),
     #[error("failed to find .dynamic section")]
-    NoDyanmicSection,
+    NoDynamicSection,
+    #[error("failed to find .dynstr section")]
+    NoDynstr,
+    #[error("failed to parse .dynstr section")]
+    DynstrParseFailed,
     #[error("failed to parse elf file: {0}")]
     ElfError(#[from] elf::ParseError),
+    #[error("dependency {0} was built for a different architecture")]
+    ArchitectureMismatch(String),
+    #[error("failed to load host library - {0}")]
+    HostLibrary(String),
+    #[error("the library requires an executable stack")]
+    ExecutableStack,
+    #[error("failed to apply {} relocations", .0.len())]
+    UnsupportedRelocations(Vec<RelocationError>),
+    #[error("malformed elf file - {0}")]
+    MalformedElf(String),
+    #[error("the library has been unloaded")]
+    Unloaded,
+    #[error("the library requires $ORIGIN but its directory can't be determined")]
+    OriginUnavailable,
+    #[error("dependency {dependency} doesn't provide required version {version}")]
+    MissingSymbolVersion { dependency: String, version: String },
     #[error("failed to add debug entry")]
     DebugEntry(std::ffi::NulError),
+    #[cfg(feature = "invoke")]
+    #[error("failed to invoke function - {0}")]
+    InvokeFailed(String),
 }
@@ -0,0 +1,43 @@
+//! Common sets of libc functions to bind to the host's implementations with JNI::override_symbols, e.g.
+//! `lib.override_symbols(&libc_overrides::alloc_funcs())`. Allocation functions are grouped so memory is always freed
+//! by the allocator that allocated it
+
+use nix::libc;
+
+pub type Overrides = Vec<(&'static str, Option<*const ()>)>;
+
+/// malloc, calloc, realloc and free
+pub fn alloc_funcs() -> Overrides {
+    vec![
+        ("malloc", Some(libc::malloc as *const ())),
+        ("calloc", Some(libc::calloc as *const ())),
+        ("realloc", Some(libc::realloc as *const ())),
+        ("free", Some(libc::free as *const ())),
+    ]
+}
+
+/// memcpy, memmove, memset, memcmp and memchr
+pub fn mem_funcs() -> Overrides {
+    vec![
+        ("memcpy", Some(libc::memcpy as *const ())),
+        ("memmove", Some(libc::memmove as *const ())),
+        ("memset", Some(libc::memset as *const ())),
+        ("memcmp", Some(libc::memcmp as *const ())),
+        ("memchr", Some(libc::memchr as *const ())),
+    ]
+}
+
+/// The usual C string functions
+pub fn string_funcs() -> Overrides {
+    vec![
+        ("strlen", Some(libc::strlen as *const ())),
+        ("strcmp", Some(libc::strcmp as *const ())),
+        ("strncmp", Some(libc::strncmp as *const ())),
+        ("strcpy", Some(libc::strcpy as *const ())),
+        ("strncpy", Some(libc::strncpy as *const ())),
+        ("strchr", Some(libc::strchr as *const ())),
+        ("strrchr", Some(libc::strrchr as *const ())),
+        ("strstr", Some(libc::strstr as *const ())),
+        ("strdup", Some(libc::strdup as *const ())),
+    ]
+}
@@ -11,6 +11,16 @@ use auxv::getauxval::Getauxval;
 use elf::{endian::AnyEndian, ElfStream};
 use log::trace;
 
+/// Where in the search order a library was found
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SearchLocation {
+    ExtraPath,       // A custom search path
+    Origin,          // The directory of the library that needs it
+    LdLibraryPath,   // LD_LIBRARY_PATH
+    Runpath,         // DT_RUNPATH of the library that needs it
+    SystemDirectory, // /lib, /usr/lib and their 32/64 bit variants
+}
+
 /// Search for a library using ld.so's search order. Custom search paths can
 /// be specified and will take priority
 pub fn locate_library(name: &str, extra_paths: Option<Vec<PathBuf>>) -> Option<PathBuf> {
@@ -20,62 +30,99 @@ pub fn locate_library(name: &str, extra_paths: Option<Vec<PathBuf>>) -> Option<P
 pub(crate) fn locate_library_internal(
     name: &str, extra_paths: Option<Vec<PathBuf>>, parent_path: Option<PathBuf>, dt_runpath: Option<PathBuf>,
 ) -> Option<PathBuf> {
-    if let Some(extra_paths) = extra_paths {
-        for path in extra_paths {
-            if let Some(lib_path) = check_directory(name, path) {
-                return Some(lib_path);
-            }
+    locate_library_with_location(name, extra_paths, parent_path, dt_runpath).map(|(lib_path, _)| lib_path)
+}
+
+// Same as locate_library_internal, also reporting which part of the search order matched
+pub(crate) fn locate_library_with_location(
+    name: &str, extra_paths: Option<Vec<PathBuf>>, parent_path: Option<PathBuf>, dt_runpath: Option<PathBuf>,
+) -> Option<(PathBuf, SearchLocation)> {
+    let directories = search_directories(extra_paths, parent_path, dt_runpath);
+    if let Some(found) = directories.iter().find_map(|(d, l)| check_directory(name, d.clone()).map(|p| (p, *l))) {
+        return Some(found);
+    }
+
+    // DT_NEEDED names and the names on disk don't always agree on the version suffix. Only once the exact name can't
+    // be found anywhere, try the less specific names (libfoo.so.1.2 -> libfoo.so.1 -> libfoo.so)
+    for variant in unversioned_names(name) {
+        if let Some(found) = directories.iter().find_map(|(d, l)| check_directory(&variant, d.clone()).map(|p| (p, *l)))
+        {
+            trace!("Using {:?} for {name}", found.0);
+            return Some(found);
         }
     }
+    // Then the more specific ones (libfoo.so -> libfoo.so.1)
+    if name.ends_with(".so") {
+        if let Some(found) = directories.iter().find_map(|(d, l)| check_directory_versioned(name, d).map(|p| (p, *l))) {
+            trace!("Using {:?} for {name}", found.0);
+            return Some(found);
+        }
+    }
+
+    None
+}
+
+fn search_directories(
+    extra_paths: Option<Vec<PathBuf>>, parent_path: Option<PathBuf>, dt_runpath: Option<PathBuf>,
+) -> Vec<(PathBuf, SearchLocation)> {
+    let mut directories: Vec<_> =
+        extra_paths.unwrap_or_default().into_iter().map(|p| (p, SearchLocation::ExtraPath)).collect();
 
-    if let Some(lib_path) = parent_path.clone().and_then(|p| check_directory(name, p)) {
-        return Some(lib_path);
+    if let Some(parent_path) = parent_path.clone() {
+        directories.push((parent_path, SearchLocation::Origin));
     }
 
     if let Ok(ld_library_path) = env::var("LD_LIBRARY_PATH") {
-        let ld_library_path = replace_tokens(ld_library_path, parent_path);
+        let ld_library_path = replace_tokens(ld_library_path, parent_path.clone());
         trace!("Checking LD_LIBRARY_PATH {ld_library_path}");
-        for path in split_paths(&ld_library_path) {
-            if let Some(lib_path) = check_directory(name, path) {
-                return Some(lib_path);
-            }
-        }
+        directories.extend(split_paths(&ld_library_path).into_iter().map(|p| (p, SearchLocation::LdLibraryPath)));
     }
 
-    if let Some(dt_runpath) = dt_runpath {
-        trace!("Checking DT_RUNPATH {dt_runpath:?}");
-        if let Some(lib_path) = check_directory(name, dt_runpath) {
-            return Some(lib_path);
-        }
+    if let Some(dt_runpath) = dt_runpath.as_ref().and_then(|p| p.to_str()) {
+        let dt_runpath = replace_tokens(dt_runpath.to_owned(), parent_path);
+        trace!("Checking DT_RUNPATH {dt_runpath}");
+        directories.extend(split_paths(&dt_runpath).into_iter().map(|p| (p, SearchLocation::Runpath)));
     }
 
     #[cfg(target_pointer_width = "64")]
-    {
-        if let Some(lib_path) = check_directory(name, PathBuf::from("/lib64/")) {
-            return Some(lib_path);
-        }
-        if let Some(lib_path) = check_directory(name, PathBuf::from("/usr/lib64/")) {
-            return Some(lib_path);
-        }
-    }
+    let system_directories = ["/lib64/", "/usr/lib64/", "/lib/", "/usr/lib/"];
     #[cfg(not(target_pointer_width = "64"))]
-    {
-        if let Some(lib_path) = check_directory(name, PathBuf::from("/lib32/")) {
-            return Some(lib_path);
-        }
-        if let Some(lib_path) = check_directory(name, PathBuf::from("/usr/lib32/")) {
-            return Some(lib_path);
-        }
-    }
+    let system_directories = ["/lib32/", "/usr/lib32/", "/lib/", "/usr/lib/"];
+    directories.extend(system_directories.into_iter().map(|d| (PathBuf::from(d), SearchLocation::SystemDirectory)));
 
-    if let Some(lib_path) = check_directory(name, PathBuf::from("/lib/")) {
-        return Some(lib_path);
-    }
-    if let Some(lib_path) = check_directory(name, PathBuf::from("/usr/lib/")) {
-        return Some(lib_path);
+    directories
+}
+
+// libfoo.so.1.2 -> [libfoo.so.1, libfoo.so]
+fn unversioned_names(name: &str) -> Vec<String> {
+    let mut names = Vec::new();
+    let mut current = name;
+    while let Some((rest, version)) = current.rsplit_once('.') {
+        if version.is_empty() || !version.chars().all(|c| c.is_ascii_digit()) || !rest.contains(".so") {
+            break;
+        }
+        names.push(rest.to_owned());
+        current = rest;
     }
+    names
+}
 
-    None
+// Find libfoo.so.N in a directory, preferring the shortest suffix as that is usually the soname the unversioned
+// development symlink points at
+fn check_directory_versioned(name: &str, directory: &PathBuf) -> Option<PathBuf> {
+    let prefix = format!("{name}.");
+    let mut candidates: Vec<String> = fs::read_dir(directory)
+        .ok()?
+        .filter_map(|e| e.ok())
+        .filter_map(|e| e.file_name().into_string().ok())
+        .filter(|file_name| {
+            file_name.strip_prefix(&prefix).is_some_and(|version| {
+                version.split('.').all(|v| !v.is_empty() && v.chars().all(|c| c.is_ascii_digit()))
+            })
+        })
+        .collect();
+    candidates.sort_by_key(|file_name| (file_name.len(), file_name.clone()));
+    candidates.iter().find_map(|file_name| check_directory(file_name, directory.clone()))
 }
 
 fn check_directory(name: &str, directory: PathBuf) -> Option<PathBuf> {
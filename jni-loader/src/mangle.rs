@@ -0,0 +1,31 @@
+use std::fmt::Write;
+
+/// Build the symbol name the JVM looks up for a native method. The class can
+/// be given with either `.` or `/` separators, e.g. `com.example.Foo`
+pub(crate) fn jni_symbol_name(class: &str, method: &str) -> String {
+    let mut symbol_name = String::from("Java_");
+    mangle(class, &mut symbol_name);
+    symbol_name.push('_');
+    mangle(method, &mut symbol_name);
+    symbol_name
+}
+
+// https://docs.oracle.com/javase/8/docs/technotes/guides/jni/spec/design.html#resolving_native_method_names
+fn mangle(name: &str, output: &mut String) {
+    for c in name.chars() {
+        match c {
+            '.' | '/' => output.push('_'),
+            '_' => output.push_str("_1"),
+            ';' => output.push_str("_2"),
+            '[' => output.push_str("_3"),
+            c if c.is_ascii_alphanumeric() => output.push(c),
+            c => {
+                // Everything else is written as UTF-16 code units, so characters outside the BMP become two escapes
+                let mut buffer = [0u16; 2];
+                for unit in c.encode_utf16(&mut buffer) {
+                    let _ = write!(output, "_0{:04x}", unit);
+                }
+            },
+        }
+    }
+}
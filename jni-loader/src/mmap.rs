@@ -6,10 +6,17 @@ fn align_up(addr: usize, page_size: usize) -> usize {
     align_down(addr + page_size - 1, page_size)
 }
 
-use std::{ffi::c_void, fmt::Debug, fs::File, num::NonZeroUsize, os::fd::AsFd};
+use std::{
+    ffi::c_void,
+    fmt::Debug,
+    fs::File,
+    io::{ErrorKind, SeekFrom},
+    num::NonZeroUsize,
+    os::fd::AsFd,
+};
 
 use elf::{
-    abi::{PF_R, PF_W, PF_X, PT_LOAD},
+    abi::{PF_R, PF_W, PF_X, PT_GNU_RELRO, PT_LOAD},
     segment::ProgramHeader,
 };
 use log::{debug, info, warn};
@@ -19,13 +26,145 @@ use nix::{
     unistd::{sysconf, SysconfVar},
 };
 
+use crate::{codewrite, reader::ReadSeek};
+
+// PROT_BTI from the kernel's arch/arm64/include/uapi/asm/mman.h, nix doesn't provide it. Executable pages mapped with
+// it only allow indirect branches to land on BTI instructions
+#[cfg(target_arch = "aarch64")]
+const PROT_BTI: ProtFlags = ProtFlags::from_bits_retain(0x10);
+
+// Where segment data comes from. Files are mapped directly, anything else is copied into anonymous memory
+pub(crate) enum MappingSource<'a> {
+    File(File),
+    Reader(&'a mut dyn ReadSeek),
+}
+
 pub struct MemoryMapping {
     pub base: usize,
-    pub size: usize,
+    pub size: usize,                  // Size of the whole reservation, including gaps between segments
+    mapped_size: usize,               // Bytes actually backed by a segment
+    code_ranges: Vec<(usize, usize)>, // Page aligned ranges of executable segments
+    detect_code_writes: bool,
+    code_prot: ProtFlags,                // Permissions for code pages once write access is removed
+    demand_ranges: Vec<(usize, usize)>,  // Code pages made executable as they are run, excludes pages shared with data
+    relro_range: Option<(usize, usize)>, // Page aligned range covered by PT_GNU_RELRO
+    relro_protected: bool,
+    file_ranges: Vec<FileRange>, // Bytes copied from the file, everything else was zero filled by the loader
+    unmapped: bool,
+}
+
+/// Part of the mapping holding bytes from the file, i.e. a segment's p_filesz bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileRange {
+    pub start: usize,     // Address in the mapping
+    pub end: usize,       // Address just past the last byte from the file
+    pub file_offset: u64, // Offset in the file of the byte at start
+    pub readable: bool,   // Whether the segment is mapped readable
+}
+
+/// Where a byte in the mapping came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Provenance {
+    File(u64),   // Copied from this offset in the file, though relocations may have changed it since
+    Synthesized, // Zero filled by the loader, e.g. .bss, the end of a segment's last page or a gap between segments
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MappingOptions {
+    guard_pages: bool,
+    detect_code_writes: bool,
+    address_hint: Option<usize>,
+    readable_gaps: bool,
+    bti: bool,
+    execute_on_demand: bool,
+    copy_to_anonymous: bool,
+    close_file: bool,
+    no_execute: bool,
+}
+
+impl MappingOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Explicitly map the space between non-adjacent segments as inaccessible, so overruns fault immediately. Turns
+    /// off readable gaps
+    pub fn with_guard_pages(mut self, guard_pages: bool) -> Self {
+        self.guard_pages = guard_pages;
+        self.readable_gaps &= !guard_pages;
+        self
+    }
+
+    // Set from the library's GNU property note rather than by the user
+    pub(crate) fn with_bti(mut self, bti: bool) -> Self {
+        self.bti = bti;
+        self
+    }
+
+    /// Make the space between segments readable zeroes instead of inaccessible, so the whole mapping can be dumped.
+    /// Turns off guard pages
+    pub fn with_readable_gaps(mut self, readable_gaps: bool) -> Self {
+        self.readable_gaps = readable_gaps;
+        self.guard_pages &= !readable_gaps;
+        self
+    }
+
+    /// Once initialized, remove write access from executable pages and record any writes to them. Pages shared with
+    /// a writable segment lose write access too, so the first write to data in them will also be recorded
+    pub fn with_code_write_detection(mut self, detect_code_writes: bool) -> Self {
+        self.detect_code_writes = detect_code_writes;
+        self
+    }
+
+    /// Once initialized, remove execute permission from code and give it back a page at a time as each page is first
+    /// run, recording which pages ran. See executed_pages(). Every page faults once, and pages shared with data keep
+    /// their permissions so they are never recorded
+    pub fn with_execute_on_demand(mut self, execute_on_demand: bool) -> Self {
+        self.execute_on_demand = execute_on_demand;
+        self
+    }
+
+    /// Copy segments from the file into anonymous memory up front instead of mapping the file. Nothing is demand paged
+    /// from the file afterwards, so it can be modified or deleted while the library is loaded
+    pub fn with_copy_to_anonymous(mut self, copy_to_anonymous: bool) -> Self {
+        self.copy_to_anonymous = copy_to_anonymous;
+        self
+    }
+
+    /// Don't keep the library's file open while it is loaded. The file is read into memory once, segments are copied
+    /// from that into anonymous memory and later ELF queries use the copy, so no file descriptor is held. Useful
+    /// when loading many libraries under a file descriptor limit, at the cost of keeping the file contents in memory
+    pub fn with_close_file(mut self, close_file: bool) -> Self {
+        self.close_file = close_file;
+        self
+    }
+
+    pub(crate) fn close_file(&self) -> bool {
+        self.close_file
+    }
+
+    /// Map every segment without execute permission, so the library can be relocated and inspected but none of its
+    /// code can run. Calling into it, including IFUNC resolvers and initializers, faults
+    pub fn with_no_execute(mut self, no_execute: bool) -> Self {
+        self.no_execute = no_execute;
+        self
+    }
+
+    pub(crate) fn no_execute(&self) -> bool {
+        self.no_execute
+    }
+
+    /// Ask for the library to be placed at or near this address, e.g. to keep it away from the host's heap. This is
+    /// only a hint, the kernel is free to put the mapping elsewhere
+    pub fn with_address_hint(mut self, address: usize) -> Self {
+        self.address_hint = Some(address);
+        self
+    }
 }
 
 struct LoadCommand {
     pub map_start: usize,  // Virtual address the mapping starts at (aligned)
+    pub data_start: usize, // Virtual address the data from the file starts at (p_vaddr)
     pub data_end: usize,   // Virtual address the data from the file ends at (p_vaddr + filesz)
     pub alloc_end: usize,  // Virtual address the data plus extra space ends at (p_vaddr + memsz)
     pub map_offset: usize, // Offset within the file that the mapping starts at (aligned)
@@ -51,16 +190,45 @@ impl Debug for LoadCommand {
     }
 }
 
+// Used when the kernel can't be asked, e.g. sysconf is blocked by seccomp. Linux uses 4K pages on x86 and almost
+// always on aarch64 too
+const DEFAULT_PAGE_SIZE: usize = 4096;
+
+// Get the system page size. Memory mappings must lie on page boundaies and be a multiple of the page size
+pub(crate) fn system_page_size() -> usize {
+    match sysconf(SysconfVar::PAGE_SIZE) {
+        Ok(Some(page_size)) if page_size > 0 => {
+            debug!("Found system page size: {page_size}");
+            return page_size as usize;
+        },
+        Ok(_) => warn!("Failed to get system page size - sysconf returned no value"),
+        Err(errno) => warn!("Failed to get system page size - {errno}"),
+    }
+    // The kernel passes the page size to every process in the auxiliary vector, which doesn't need a syscall to read
+    let page_size = unsafe { nix::libc::getauxval(nix::libc::AT_PAGESZ) } as usize;
+    if page_size.is_power_of_two() {
+        debug!("Found page size in the auxiliary vector: {page_size}");
+        return page_size;
+    }
+    warn!("Falling back to the default page size: {DEFAULT_PAGE_SIZE}");
+    DEFAULT_PAGE_SIZE
+}
+
 impl MemoryMapping {
-    pub fn new(file: File, program_headers: &[ProgramHeader]) -> Result<Self, String> {
-        // Get the system page size. Memory mappings must lie on page boundaies and be a multiple of the page size
-        let page_size = sysconf(SysconfVar::PAGE_SIZE).map_err(|e| e.to_string())?;
-        let Some(page_size) = page_size else {
-            warn!("Failed to get system page size");
-            return Err("Page size cannot be empty".to_string());
-        };
-        let page_size = page_size as usize;
-        debug!("Found system page size: {page_size}");
+    // Lay the segments out using a chosen granularity, e.g. to act like a 64K page host on a 4K page one. It must be
+    // a multiple of the real page size
+    pub(crate) fn with_page_size(
+        mut source: MappingSource, program_headers: &[ProgramHeader], options: &MappingOptions, page_size: usize,
+    ) -> Result<Self, String> {
+        if !page_size.is_power_of_two() {
+            return Err(format!("Page size {page_size:#x} is not a power of two"));
+        }
+        let real_page_size = system_page_size();
+        if page_size % real_page_size != 0 {
+            return Err(format!(
+                "Page size {page_size:#x} is not a multiple of the system page size {real_page_size:#x}"
+            ));
+        }
 
         // Get load commands from program headers
         let mut load_commands = vec![];
@@ -69,6 +237,7 @@ impl MemoryMapping {
             if program_header.p_type == PT_LOAD {
                 let mut cmd = LoadCommand {
                     map_start: align_down(program_header.p_vaddr as usize, page_size),
+                    data_start: program_header.p_vaddr as usize,
                     data_end: (program_header.p_vaddr + program_header.p_filesz) as usize,
                     alloc_end: (program_header.p_vaddr + program_header.p_memsz) as usize,
                     map_align: 0,
@@ -82,13 +251,22 @@ impl MemoryMapping {
                 if program_header.p_flags & PF_W == PF_W {
                     cmd.prot |= ProtFlags::PROT_WRITE;
                 }
-                if program_header.p_flags & PF_X == PF_X {
+                if program_header.p_flags & PF_X == PF_X && !options.no_execute {
                     cmd.prot |= ProtFlags::PROT_EXEC;
+                    #[cfg(target_arch = "aarch64")]
+                    if options.bti {
+                        cmd.prot |= PROT_BTI;
+                    }
                 }
                 debug!("Found {cmd:?}");
                 load_commands.push(cmd);
             }
         }
+        if load_commands.is_empty() {
+            return Err("No PT_LOAD segments to map".to_string());
+        }
+        // Everything below relies on the segments being in address order, which is conventional but not required
+        load_commands.sort_by_key(|c| c.data_start);
         // TODO: How to handle alignment larger than page size?
         // ld.so doesn't seem to do anything special, the mapping base is not aligned nor are the segments
         debug!("Load alignment: {load_alignment:#010x}");
@@ -98,14 +276,31 @@ impl MemoryMapping {
 
         // This should always be zero. Subtract it just in case
         let virtual_mapping_base = load_commands[0].map_start;
-        let mapping_size = align_up(load_commands[load_commands.len() - 1].alloc_end - virtual_mapping_base, page_size);
+        let mapping_end = load_commands.iter().map(|c| c.alloc_end).max().unwrap_or(virtual_mapping_base);
+        let mapping_size = align_up(mapping_end - virtual_mapping_base, page_size);
+
+        #[cfg(target_arch = "aarch64")]
+        let code_prot = if options.bti {
+            ProtFlags::PROT_READ | ProtFlags::PROT_EXEC | PROT_BTI
+        } else {
+            ProtFlags::PROT_READ | ProtFlags::PROT_EXEC
+        };
+        #[cfg(not(target_arch = "aarch64"))]
+        let code_prot = ProtFlags::PROT_READ | ProtFlags::PROT_EXEC;
 
+        // Anonymous memory is zero filled, so readable gaps are deterministic without writing to them
+        let reservation_prot = if options.readable_gaps {
+            ProtFlags::PROT_READ
+        } else {
+            ProtFlags::PROT_NONE
+        };
         // Reserve enough pages to contain all the mapped program headers. This will be divided later
+        let address_hint = options.address_hint.and_then(|address| NonZeroUsize::new(align_up(address, page_size)));
         let mapping_base = match unsafe {
             mmap::<File>(
-                None,
+                address_hint,
                 NonZeroUsize::new_unchecked(mapping_size),
-                ProtFlags::PROT_NONE, /* TODO: Obfuscation techniques may rely on gaps between mapped areas. Will PROT_NONE cause a segfault? */
+                reservation_prot, /* TODO: Obfuscation techniques may rely on gaps between mapped areas. Will PROT_NONE cause a segfault? */
                 MapFlags::MAP_PRIVATE | MapFlags::MAP_ANONYMOUS,
                 None,
                 0,
@@ -118,6 +313,9 @@ impl MemoryMapping {
         info!("Created mapping {:#018x}-{:#018x}", mapping_base, mapping_base + mapping_size);
         #[cfg(not(target_pointer_width = "64"))]
         info!("Created mapping {:#010x}-{:#010x}", mapping_base, mapping_base + mapping_size);
+        if address_hint.is_some_and(|address| address.get() != mapping_base) {
+            debug!("Address hint was not honoured");
+        }
 
         // We now have a block of memory large enough to contain the mapped file, so lets begin loading it. Each
         // segment may have different permissions and permissions are granular to each page, however two segments
@@ -132,34 +330,191 @@ impl MemoryMapping {
         // that final page should also be initialized to zeroes, for non-writable segments this requires granting
         // write permissions temporarily
 
-        for (mut i, load_command) in load_commands.iter().enumerate() {
+        let code_ranges = load_commands
+            .iter()
+            .filter(|c| c.prot.contains(ProtFlags::PROT_EXEC))
+            .map(|c| {
+                let start = mapping_base + c.map_start - virtual_mapping_base;
+                (start, align_up(mapping_base + c.alloc_end - virtual_mapping_base, page_size))
+            })
+            .collect();
+        let demand_ranges = if options.execute_on_demand {
+            let shares_page = |page: usize| {
+                load_commands.iter().any(|c| {
+                    !c.prot.contains(ProtFlags::PROT_EXEC)
+                        && c.map_start < page + page_size
+                        && align_up(c.alloc_end, page_size) > page
+                })
+            };
+            load_commands
+                .iter()
+                .filter(|c| c.prot.contains(ProtFlags::PROT_EXEC))
+                .filter_map(|c| {
+                    let mut start = c.map_start;
+                    let mut end = align_up(c.alloc_end, page_size);
+                    if shares_page(start) {
+                        start += page_size;
+                    }
+                    if end > start && shares_page(end - page_size) {
+                        end -= page_size;
+                    }
+                    (start < end).then(|| {
+                        (mapping_base + start - virtual_mapping_base, mapping_base + end - virtual_mapping_base)
+                    })
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
+        // Like ld.so only whole pages are made read only, the end is rounded down so that the rest of a page shared
+        // with writable data stays writable
+        let relro_range = program_headers.iter().find(|h| h.p_type == PT_GNU_RELRO).and_then(|h| {
+            let start = mapping_base + align_down(h.p_vaddr as usize, page_size) - virtual_mapping_base;
+            let end = mapping_base + align_down((h.p_vaddr + h.p_memsz) as usize, page_size) - virtual_mapping_base;
+            (start < end).then_some((start, end))
+        });
+        let file_ranges = program_headers
+            .iter()
+            .filter(|h| h.p_type == PT_LOAD && h.p_filesz != 0)
+            .map(|h| FileRange {
+                start: mapping_base + h.p_vaddr as usize - virtual_mapping_base,
+                end: mapping_base + (h.p_vaddr + h.p_filesz) as usize - virtual_mapping_base,
+                file_offset: h.p_offset,
+                readable: h.p_flags & PF_R == PF_R,
+            })
+            .collect();
+        let mut mapped_size = 0;
+        let mut mapped_end = mapping_base;
+        for (i, load_command) in load_commands.iter().enumerate() {
             // Map the data from the file
             let aligned_data_addr = mapping_base + load_command.map_start - virtual_mapping_base;
             let aligned_data_size = align_up(load_command.data_end - load_command.map_start, page_size);
             let prot = load_command.prot;
             let aligned_data_offset = load_command.map_offset;
+            let last_data_page_addr = aligned_data_addr + aligned_data_size - page_size;
+
+            // Check for pages shared with previous segments. Shared pages need the union of every segment's
+            // permissions, regardless of which segment was mapped last
+            let mut have_overlaps = false;
+            let mut overlapped_prot = prot;
+            let mut prev_index = i;
+            loop {
+                if prev_index == 0 {
+                    break;
+                }
+                prev_index -= 1;
+                let prev_command = &load_commands[prev_index];
+                let aligned_prev_end = align_up(prev_command.alloc_end, page_size);
+                if aligned_prev_end > load_command.map_start {
+                    have_overlaps = true;
+                    overlapped_prot |= prev_command.prot;
+                } else {
+                    break;
+                }
+            }
+            // A segment without any file data (pure .bss) only needs zero filled memory, mapping zero bytes from the
+            // file isn't possible. A first page shared with the previous segment is already mapped, and zeroed past
+            // the end of that segment's data
+            if load_command.data_start == load_command.data_end {
+                let alloc_start = if have_overlaps {
+                    align_up(load_command.data_start, page_size)
+                } else {
+                    load_command.map_start
+                };
+                let alloc_end = align_up(load_command.alloc_end, page_size);
+                let alloc_addr = mapping_base + alloc_start - virtual_mapping_base;
+                if alloc_start < alloc_end {
+                    if let Err(errno) = unsafe {
+                        mmap::<File>(
+                            Some(NonZeroUsize::new_unchecked(alloc_addr)),
+                            NonZeroUsize::new_unchecked(alloc_end - alloc_start),
+                            prot,
+                            MapFlags::MAP_PRIVATE | MapFlags::MAP_ANONYMOUS | MapFlags::MAP_FIXED,
+                            None,
+                            0,
+                        )
+                    } {
+                        let _ = unsafe { munmap(mapping_base as *mut c_void, mapping_size) };
+                        return Err(errno.to_string());
+                    };
+                }
+                if have_overlaps {
+                    if let Err(errno) =
+                        unsafe { mprotect(aligned_data_addr as *mut c_void, page_size, overlapped_prot) }
+                    {
+                        let _ = unsafe { munmap(mapping_base as *mut c_void, mapping_size) };
+                        return Err(errno.to_string());
+                    };
+                }
+                let segment_end = mapping_base + alloc_end - virtual_mapping_base;
+                #[cfg(target_pointer_width = "64")]
+                debug!("Mapped zero filled {:#018x}-{:#018x}", alloc_addr, segment_end);
+                #[cfg(not(target_pointer_width = "64"))]
+                debug!("Mapped zero filled {:#010x}-{:#010x}", alloc_addr, segment_end);
+                mapped_size += segment_end.saturating_sub(std::cmp::max(aligned_data_addr, mapped_end));
+                mapped_end = std::cmp::max(mapped_end, segment_end);
+                continue;
+            }
+
+            // The last data page may also be the shared first page
+            let last_data_page_prot = if have_overlaps && last_data_page_addr == aligned_data_addr {
+                overlapped_prot
+            } else {
+                prot
+            };
             // TODO: What if load_alignment > page_size?
-            if let Err(errno) = unsafe {
-                mmap(
-                    Some(NonZeroUsize::new_unchecked(aligned_data_addr)),
-                    NonZeroUsize::new_unchecked(aligned_data_size),
-                    prot,
-                    MapFlags::MAP_PRIVATE | MapFlags::MAP_FIXED,
-                    Some(file.as_fd()),
-                    aligned_data_offset as i64,
-                )
-            } {
+            let map_result = match source {
+                MappingSource::File(ref mut file) if options.copy_to_anonymous => {
+                    copy_from_reader(file, aligned_data_addr, aligned_data_size, aligned_data_offset, prot)
+                },
+                MappingSource::File(ref file) => unsafe {
+                    mmap(
+                        Some(NonZeroUsize::new_unchecked(aligned_data_addr)),
+                        NonZeroUsize::new_unchecked(aligned_data_size),
+                        prot,
+                        MapFlags::MAP_PRIVATE | MapFlags::MAP_FIXED,
+                        Some(file.as_fd()),
+                        aligned_data_offset as i64,
+                    )
+                }
+                .map(|_| ())
+                .map_err(|errno| errno.to_string()),
+                MappingSource::Reader(ref mut reader) => {
+                    copy_from_reader(*reader, aligned_data_addr, aligned_data_size, aligned_data_offset, prot)
+                },
+            };
+            if let Err(error) = map_result {
                 let _ = unsafe { munmap(mapping_base as *mut c_void, mapping_size) };
-                return Err(errno.to_string());
+                return Err(error);
             };
 
+            // Mapping this segment replaced the shared first page with data from the file, which undoes any zeroing
+            // done for the previous segment's extra space. Zero it again
+            if have_overlaps {
+                let prev_command = &load_commands[i - 1];
+                let zero_start = std::cmp::max(prev_command.data_end, load_command.map_start);
+                let zero_end = std::cmp::min(
+                    std::cmp::min(prev_command.alloc_end, load_command.data_start),
+                    load_command.map_start + page_size,
+                );
+                if zero_start < zero_end {
+                    if let Err(errno) = unsafe {
+                        mprotect(aligned_data_addr as *mut c_void, page_size, overlapped_prot | ProtFlags::PROT_WRITE)
+                    } {
+                        let _ = unsafe { munmap(mapping_base as *mut c_void, mapping_size) };
+                        return Err(errno.to_string());
+                    };
+                    let zero_addr = mapping_base + zero_start - virtual_mapping_base;
+                    let _ = unsafe { memset(zero_addr as *mut c_void, 0, zero_end - zero_start) };
+                }
+            }
+
             // Grant us write access if needed
             // NOTE: Changing the permissions of one page causes it to become a second mapping
-            if prot & ProtFlags::PROT_WRITE != ProtFlags::PROT_WRITE {
-                let last_data_page_addr = aligned_data_addr + aligned_data_size - page_size;
-                if let Err(errno) =
-                    unsafe { mprotect(last_data_page_addr as *mut c_void, page_size, prot | ProtFlags::PROT_WRITE) }
-                {
+            if last_data_page_prot & ProtFlags::PROT_WRITE != ProtFlags::PROT_WRITE {
+                if let Err(errno) = unsafe {
+                    mprotect(last_data_page_addr as *mut c_void, page_size, last_data_page_prot | ProtFlags::PROT_WRITE)
+                } {
                     let _ = unsafe { munmap(mapping_base as *mut c_void, mapping_size) };
                     return Err(errno.to_string());
                 };
@@ -171,9 +526,10 @@ impl MemoryMapping {
             let _ = unsafe { memset(data_end_addr as *mut c_void, 0, data_space_size) };
 
             // Restore the permissions if needed
-            if prot & ProtFlags::PROT_WRITE != ProtFlags::PROT_WRITE {
-                let last_data_page_addr = aligned_data_addr + aligned_data_size - page_size;
-                if let Err(errno) = unsafe { mprotect(last_data_page_addr as *mut c_void, page_size, prot) } {
+            if last_data_page_prot & ProtFlags::PROT_WRITE != ProtFlags::PROT_WRITE {
+                if let Err(errno) =
+                    unsafe { mprotect(last_data_page_addr as *mut c_void, page_size, last_data_page_prot) }
+                {
                     let _ = unsafe { munmap(mapping_base as *mut c_void, mapping_size) };
                     return Err(errno.to_string());
                 };
@@ -235,23 +591,15 @@ impl MemoryMapping {
                 );
             }
 
-            // Check for overlapping pages and handle accordingly
-            let mut have_overlaps = false;
-            let mut overlapped_prot = prot;
-            loop {
-                if i == 0 {
-                    break;
-                }
-                i -= 1;
-                let prev_command = &load_commands[i];
-                let aligned_prev_end = align_up(prev_command.alloc_end, page_size);
-                if aligned_prev_end > load_command.map_start {
-                    have_overlaps = true;
-                    overlapped_prot |= prev_command.prot;
-                } else {
-                    break;
-                }
-            }
+            // Segments can share a page, so only count the part past the end of the previous segment
+            let segment_end = std::cmp::max(
+                aligned_data_addr + aligned_data_size,
+                align_up(mapping_base + load_command.alloc_end - virtual_mapping_base, page_size),
+            );
+            mapped_size += segment_end.saturating_sub(std::cmp::max(aligned_data_addr, mapped_end));
+            mapped_end = std::cmp::max(mapped_end, segment_end);
+
+            // Combine permissions for the first page if it's shared with previous segments
             if have_overlaps {
                 if let Err(errno) = unsafe { mprotect(aligned_data_addr as *mut c_void, page_size, overlapped_prot) } {
                     let _ = unsafe { munmap(mapping_base as *mut c_void, mapping_size) };
@@ -260,16 +608,222 @@ impl MemoryMapping {
             }
         }
 
-        Ok(Self { base: mapping_base, size: mapping_size })
+        // The gaps are already covered by the PROT_NONE reservation, but nothing guarantees they stay that way. Map
+        // them again so the intent is explicit. Segments that share a page or are directly adjacent can't have a
+        // guard page without moving them, which would break the relative offsets between them
+        if options.guard_pages {
+            for commands in load_commands.windows(2) {
+                let gap_start = align_up(commands[0].alloc_end, page_size);
+                let gap_end = commands[1].map_start;
+                if gap_start >= gap_end {
+                    debug!("No space for a guard page between {:?} and {:?}", commands[0], commands[1]);
+                    continue;
+                }
+                let gap_addr = mapping_base + gap_start - virtual_mapping_base;
+                if let Err(errno) = unsafe {
+                    mmap::<File>(
+                        Some(NonZeroUsize::new_unchecked(gap_addr)),
+                        NonZeroUsize::new_unchecked(gap_end - gap_start),
+                        ProtFlags::PROT_NONE,
+                        MapFlags::MAP_PRIVATE | MapFlags::MAP_ANONYMOUS | MapFlags::MAP_FIXED | MapFlags::MAP_NORESERVE,
+                        None,
+                        0,
+                    )
+                } {
+                    let _ = unsafe { munmap(mapping_base as *mut c_void, mapping_size) };
+                    return Err(errno.to_string());
+                };
+                #[cfg(target_pointer_width = "64")]
+                debug!("Guard pages {:#018x}-{:#018x}", gap_addr, gap_addr + gap_end - gap_start);
+                #[cfg(not(target_pointer_width = "64"))]
+                debug!("Guard pages {:#010x}-{:#010x}", gap_addr, gap_addr + gap_end - gap_start);
+            }
+        }
+
+        Ok(Self {
+            base: mapping_base,
+            size: mapping_size,
+            mapped_size,
+            code_ranges,
+            detect_code_writes: options.detect_code_writes,
+            code_prot,
+            demand_ranges,
+            relro_range,
+            relro_protected: false,
+            file_ranges,
+            unmapped: false,
+        })
+    }
+
+    pub(crate) fn detect_code_writes(&self) -> bool {
+        self.detect_code_writes
+    }
+
+    // Drop write access from executable pages and start recording writes to them
+    pub(crate) fn protect_code(&self) -> Result<(), String> {
+        for &(start, end) in self.code_ranges.iter() {
+            if let Err(errno) = unsafe { mprotect(start as *mut c_void, end - start, self.code_prot) } {
+                return Err(errno.to_string());
+            }
+            codewrite::watch(start, end)?;
+            #[cfg(target_pointer_width = "64")]
+            debug!("Watching {:#018x}-{:#018x} for writes", start, end);
+            #[cfg(not(target_pointer_width = "64"))]
+            debug!("Watching {:#010x}-{:#010x} for writes", start, end);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn execute_on_demand(&self) -> bool {
+        !self.demand_ranges.is_empty()
+    }
+
+    // Drop execute permission from code pages, the SIGSEGV handler gives it back as each one is run
+    pub(crate) fn protect_code_until_run(&self) -> Result<(), String> {
+        for &(start, end) in self.demand_ranges.iter() {
+            if let Err(errno) = unsafe { mprotect(start as *mut c_void, end - start, ProtFlags::PROT_READ) } {
+                return Err(errno.to_string());
+            }
+            codewrite::watch_execution(start, end, self.code_prot.bits())?;
+            #[cfg(target_pointer_width = "64")]
+            debug!("Executing {:#018x}-{:#018x} on demand", start, end);
+            #[cfg(not(target_pointer_width = "64"))]
+            debug!("Executing {:#010x}-{:#010x} on demand", start, end);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn relro_range(&self) -> Option<(usize, usize)> {
+        self.relro_range
+    }
+
+    // Make the PT_GNU_RELRO region read only, once relocations that target it have been applied
+    pub(crate) fn protect_relro(&mut self) -> Result<(), String> {
+        let Some((start, end)) = self.relro_range else {
+            return Ok(());
+        };
+        if let Err(errno) = unsafe { mprotect(start as *mut c_void, end - start, ProtFlags::PROT_READ) } {
+            return Err(errno.to_string());
+        }
+        self.relro_protected = true;
+        #[cfg(target_pointer_width = "64")]
+        debug!("Protected RELRO {:#018x}-{:#018x}", start, end);
+        #[cfg(not(target_pointer_width = "64"))]
+        debug!("Protected RELRO {:#010x}-{:#010x}", start, end);
+        Ok(())
+    }
+
+    // Temporarily give write access back to the RELRO region, returns whether it needs protecting again afterwards
+    pub(crate) fn unprotect_relro(&mut self) -> Result<bool, String> {
+        let (Some((start, end)), true) = (self.relro_range, self.relro_protected) else {
+            return Ok(false);
+        };
+        let prot = ProtFlags::PROT_READ | ProtFlags::PROT_WRITE;
+        if let Err(errno) = unsafe { mprotect(start as *mut c_void, end - start, prot) } {
+            return Err(errno.to_string());
+        }
+        self.relro_protected = false;
+        Ok(true)
+    }
+
+    /// Size of the whole reserved address range, including any gaps between segments
+    pub fn reserved_size(&self) -> usize {
+        self.size
+    }
+
+    /// Number of bytes actually mapped for segments, excluding gaps between them
+    pub fn mapped_size(&self) -> usize {
+        self.mapped_size
+    }
+
+    /// The parts of the mapping that hold bytes from the file, in segment order
+    pub fn file_ranges(&self) -> &[FileRange] {
+        &self.file_ranges
+    }
+
+    /// Whether the byte at `address` came from the file or was filled in by the loader. None outside the mapping
+    pub fn provenance(&self, address: usize) -> Option<Provenance> {
+        if address < self.base || address >= self.base + self.size {
+            return None;
+        }
+        let file_range = self.file_ranges.iter().find(|r| address >= r.start && address < r.end);
+        Some(
+            file_range
+                .map_or(Provenance::Synthesized, |r| Provenance::File(r.file_offset + (address - r.start) as u64)),
+        )
+    }
+
+    /// Rebuild the file from the mapped segments, using the bytes as they are now. Anything not loaded from the file
+    /// (headers outside a segment, section headers, non-readable segments) is left as zeroes, and relocated values
+    /// differ from the file unless called before initialize()
+    pub fn file_image(&self) -> Vec<u8> {
+        if self.unmapped {
+            return Vec::new();
+        }
+        let image_size = self.file_ranges.iter().map(|r| r.file_offset as usize + r.end - r.start).max().unwrap_or(0);
+        let mut image = vec![0u8; image_size];
+        for range in self.file_ranges.iter().filter(|r| r.readable) {
+            let data = unsafe { std::slice::from_raw_parts(range.start as *const u8, range.end - range.start) };
+            let file_offset = range.file_offset as usize;
+            image[file_offset..file_offset + data.len()].copy_from_slice(data);
+        }
+        image
     }
 }
 
-impl Drop for MemoryMapping {
-    fn drop(&mut self) {
+// Fill an anonymous mapping with data from a reader. Reading stops early at the end of the reader, like mapping a file
+// the rest of the space is left as zeroes
+fn copy_from_reader(
+    reader: &mut dyn ReadSeek, addr: usize, size: usize, offset: usize, prot: ProtFlags,
+) -> Result<(), String> {
+    unsafe {
+        mmap::<File>(
+            Some(NonZeroUsize::new_unchecked(addr)),
+            NonZeroUsize::new_unchecked(size),
+            ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+            MapFlags::MAP_PRIVATE | MapFlags::MAP_ANONYMOUS | MapFlags::MAP_FIXED,
+            None,
+            0,
+        )
+    }
+    .map_err(|errno| errno.to_string())?;
+    reader.seek(SeekFrom::Start(offset as u64)).map_err(|e| e.to_string())?;
+    let buffer = unsafe { std::slice::from_raw_parts_mut(addr as *mut u8, size) };
+    let mut read = 0;
+    while read < size {
+        match reader.read(&mut buffer[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(error) if error.kind() == ErrorKind::Interrupted => continue,
+            Err(error) => return Err(error.to_string()),
+        }
+    }
+    unsafe { mprotect(addr as *mut c_void, size, prot) }.map_err(|errno| errno.to_string())
+}
+
+impl MemoryMapping {
+    // Release the address range early, before the mapping is dropped. Does nothing if it's already been released
+    pub(crate) fn unmap(&mut self) {
+        if self.unmapped {
+            return;
+        }
+        self.unmapped = true;
         #[cfg(target_pointer_width = "64")]
         info!("Unmapping {:#018x}-{:#018x}", self.base, self.base + self.size);
         #[cfg(not(target_pointer_width = "64"))]
         info!("Unmapping {:#010x}-{:#010x}", self.base, self.base + self.size);
+        for &(start, _) in self.code_ranges.iter() {
+            codewrite::unwatch(start);
+        }
+        for &(start, _) in self.demand_ranges.iter() {
+            codewrite::unwatch_execution(start);
+        }
         let _ = unsafe { munmap(self.base as *mut c_void, self.size) };
     }
 }
+
+impl Drop for MemoryMapping {
+    fn drop(&mut self) {
+        self.unmap();
+    }
+}
@@ -4,6 +4,11 @@
 mod asm;
 pub use asm::plt_callback_trampoline as trampoline;
 
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
 use super::JNI;
 
 #[repr(C)]
@@ -22,3 +27,30 @@ extern "C" fn plt_callback(jni_addr: *mut JNI, reloc_index: usize) -> usize {
     let jni_ptr = unsafe { &mut *jni_addr };
     jni_ptr.plt_callback(reloc_index).unwrap_or(0xBADBABE)
 }
+
+/// Import call counts collected by JNI::trace_imports(). It stays readable after tracing stops
+#[derive(Clone)]
+pub struct ImportTracer {
+    calls: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+impl ImportTracer {
+    pub(crate) fn new(calls: Arc<Mutex<HashMap<String, usize>>>) -> Self {
+        Self { calls }
+    }
+
+    /// Every import that has been called and how many times, most called first
+    pub fn calls(&self) -> Vec<(String, usize)> {
+        let mut calls: Vec<_> = self.calls.lock().unwrap().iter().map(|(name, &count)| (name.clone(), count)).collect();
+        calls.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        calls
+    }
+
+    pub fn call_count(&self, symbol_name: &str) -> usize {
+        self.calls.lock().unwrap().get(symbol_name).copied().unwrap_or(0)
+    }
+
+    pub fn clear(&self) {
+        self.calls.lock().unwrap().clear();
+    }
+}
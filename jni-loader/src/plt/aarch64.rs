@@ -16,6 +16,8 @@ pub unsafe extern "C" fn plt_callback_trampoline() {
     // [sp + 8] contains link register for original call
 
     asm!(
+        // Landing pad for the indirect branch from PLT0 when BTI is enforced, a no-op otherwise
+        "hint #34", // bti c
         // Save registers
         "sub sp, sp, {SAVED_REGISTERS_SPACE}",
         "stp x0, x1, [sp, {SAVED_X0_X1_OFFSET}]",
@@ -0,0 +1,72 @@
+use std::io::{Read, Seek};
+
+use elf::{
+    abi::{EM_AARCH64, PT_GNU_PROPERTY, PT_NOTE},
+    endian::{AnyEndian, EndianParse},
+    file::Class,
+    ElfStream,
+};
+
+const NT_GNU_PROPERTY_TYPE_0: u32 = 5;
+const GNU_PROPERTY_AARCH64_FEATURE_1_AND: u32 = 0xc0000000;
+const GNU_PROPERTY_AARCH64_FEATURE_1_BTI: u32 = 1 << 0;
+
+// Whether an aarch64 library was built with branch target identification, according to its .note.gnu.property note
+pub(crate) fn requests_bti<S: Read + Seek>(elf_file: &mut ElfStream<AnyEndian, S>) -> bool {
+    if elf_file.ehdr.e_machine != EM_AARCH64 {
+        return false;
+    }
+    let (endianness, class) = (elf_file.ehdr.endianness, elf_file.ehdr.class);
+    // PT_GNU_PROPERTY points straight at the note, older linkers only include it in a PT_NOTE
+    let segments: Vec<_> =
+        elf_file.segments().iter().filter(|s| s.p_type == PT_GNU_PROPERTY || s.p_type == PT_NOTE).copied().collect();
+    for segment in segments {
+        let Ok(data) = elf_file.segment_data(&segment) else {
+            continue;
+        };
+        if let Some(features) = aarch64_features(endianness, class, data) {
+            return features & GNU_PROPERTY_AARCH64_FEATURE_1_BTI != 0;
+        }
+    }
+    false
+}
+
+// Walk the notes looking for the GNU property note and return its AArch64 feature bits. Property notes are aligned to
+// the address size, unlike other notes which are always 4 byte aligned
+fn aarch64_features(endianness: AnyEndian, class: Class, data: &[u8]) -> Option<u32> {
+    let align = match class {
+        Class::ELF32 => 4,
+        Class::ELF64 => 8,
+    };
+    let mut offset = 0;
+    while offset + 12 <= data.len() {
+        let name_size = endianness.parse_u32_at(&mut offset, data).ok()? as usize;
+        let desc_size = endianness.parse_u32_at(&mut offset, data).ok()? as usize;
+        let note_type = endianness.parse_u32_at(&mut offset, data).ok()?;
+        let name = data.get(offset..offset + name_size)?;
+        let desc_start = offset + align_up(name_size, 4);
+        let desc = data.get(desc_start..desc_start + desc_size)?;
+        if note_type == NT_GNU_PROPERTY_TYPE_0 && name == b"GNU\0" {
+            return property_features(endianness, align, desc);
+        }
+        offset = desc_start + align_up(desc_size, 4);
+    }
+    None
+}
+
+fn property_features(endianness: AnyEndian, align: usize, desc: &[u8]) -> Option<u32> {
+    let mut offset = 0;
+    while offset + 8 <= desc.len() {
+        let property_type = endianness.parse_u32_at(&mut offset, desc).ok()?;
+        let data_size = endianness.parse_u32_at(&mut offset, desc).ok()? as usize;
+        if property_type == GNU_PROPERTY_AARCH64_FEATURE_1_AND && data_size >= 4 {
+            return endianness.parse_u32_at(&mut offset, desc).ok();
+        }
+        offset = align_up(offset + data_size, align);
+    }
+    None
+}
+
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
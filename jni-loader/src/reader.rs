@@ -0,0 +1,36 @@
+use std::{
+    io::{Read, Result, Seek, SeekFrom},
+    sync::{Arc, Mutex},
+};
+
+pub(crate) trait ReadSeek: Read + Seek {}
+impl<T: Read + Seek> ReadSeek for T {}
+
+// Lets the ELF parser and the memory mapper read from the same underlying reader
+pub(crate) struct SharedReader<R: Read + Seek> {
+    reader: Arc<Mutex<R>>,
+}
+
+impl<R: Read + Seek> SharedReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self { reader: Arc::new(Mutex::new(reader)) }
+    }
+}
+
+impl<R: Read + Seek> Clone for SharedReader<R> {
+    fn clone(&self) -> Self {
+        Self { reader: self.reader.clone() }
+    }
+}
+
+impl<R: Read + Seek> Read for SharedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.reader.lock().unwrap().read(buf)
+    }
+}
+
+impl<R: Read + Seek> Seek for SharedReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        self.reader.lock().unwrap().seek(pos)
+    }
+}
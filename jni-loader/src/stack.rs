@@ -0,0 +1,101 @@
+use std::{
+    ffi::c_void,
+    fs::File,
+    num::NonZeroUsize,
+    panic::{catch_unwind, resume_unwind, AssertUnwindSafe},
+};
+
+use log::debug;
+use nix::sys::mman::{mmap, mprotect, munmap, MapFlags, ProtFlags};
+
+use crate::mmap::system_page_size;
+
+// The closure and where its result goes, shared with the trampoline running on the new stack
+struct Call<F, R> {
+    f: Option<F>,
+    result: Option<std::thread::Result<R>>,
+}
+
+// Runs on the new stack. Panics can't unwind through the stack switch, so they are caught here and resumed once the
+// original stack is back
+extern "C" fn trampoline<F: FnOnce() -> R, R>(data: *mut c_void) {
+    let call = unsafe { &mut *(data as *mut Call<F, R>) };
+    let f = call.f.take().unwrap();
+    call.result = Some(catch_unwind(AssertUnwindSafe(f)));
+}
+
+// Run `f` on a freshly allocated stack of at least `stack_size` bytes, with an inaccessible guard page below it
+pub(crate) fn call_on_stack<F: FnOnce() -> R, R>(f: F, stack_size: usize) -> Result<R, String> {
+    let page_size = system_page_size();
+    let stack_size = (stack_size.max(page_size) + page_size - 1) & !(page_size - 1);
+    let mapping_size = stack_size + page_size;
+    let mapping_base = unsafe {
+        mmap::<File>(
+            None,
+            NonZeroUsize::new_unchecked(mapping_size),
+            ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+            MapFlags::MAP_PRIVATE | MapFlags::MAP_ANONYMOUS | MapFlags::MAP_STACK,
+            None,
+            0,
+        )
+    }
+    .map_err(|errno| errno.to_string())? as usize;
+    if let Err(errno) = unsafe { mprotect(mapping_base as *mut c_void, page_size, ProtFlags::PROT_NONE) } {
+        let _ = unsafe { munmap(mapping_base as *mut c_void, mapping_size) };
+        return Err(errno.to_string());
+    }
+    // Stacks grow down, the top is 16 byte aligned as both architectures require at a call
+    let stack_top = mapping_base + mapping_size;
+    #[cfg(target_pointer_width = "64")]
+    debug!("Switching to stack {:#018x}-{:#018x}", mapping_base + page_size, stack_top);
+    #[cfg(not(target_pointer_width = "64"))]
+    debug!("Switching to stack {:#010x}-{:#010x}", mapping_base + page_size, stack_top);
+
+    let mut call = Call { f: Some(f), result: None };
+    unsafe { switch_and_call(stack_top, trampoline::<F, R>, &mut call as *mut Call<F, R> as *mut c_void) };
+    let _ = unsafe { munmap(mapping_base as *mut c_void, mapping_size) };
+    match call.result {
+        Some(Ok(result)) => Ok(result),
+        Some(Err(panic)) => resume_unwind(panic),
+        None => Err("Closure didn't run".to_string()),
+    }
+}
+
+// Point the stack pointer at `stack_top`, call `f(data)` and put the original stack pointer back. It is kept in a
+// callee-saved register so `f` preserves it for us
+#[cfg(target_arch = "x86_64")]
+unsafe fn switch_and_call(stack_top: usize, f: extern "C" fn(*mut c_void), data: *mut c_void) {
+    std::arch::asm!(
+        "mov r12, rsp",
+        "mov rsp, {stack_top}",
+        "call {f}",
+        "mov rsp, r12",
+        stack_top = in(reg) stack_top,
+        f = in(reg) f,
+        in("rdi") data,
+        out("r12") _,
+        clobber_abi("C"),
+    );
+}
+
+#[cfg(target_arch = "aarch64")]
+unsafe fn switch_and_call(stack_top: usize, f: extern "C" fn(*mut c_void), data: *mut c_void) {
+    std::arch::asm!(
+        "mov x20, sp",
+        "mov sp, {stack_top}",
+        "blr {f}",
+        "mov sp, x20",
+        stack_top = in(reg) stack_top,
+        f = in(reg) f,
+        in("x0") data,
+        out("x20") _,
+        clobber_abi("C"),
+    );
+}
+
+// No stack switching code for this architecture, the closure runs on the current stack
+#[cfg(all(not(target_arch = "x86_64"), not(target_arch = "aarch64")))]
+unsafe fn switch_and_call(_stack_top: usize, f: extern "C" fn(*mut c_void), data: *mut c_void) {
+    log::warn!("Stack switching isn't supported on this architecture, using the current stack");
+    f(data);
+}
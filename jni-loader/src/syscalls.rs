@@ -0,0 +1,97 @@
+use std::{ffi::c_int, sync::Mutex};
+
+use nix::libc;
+
+/// A call to one of the intercepted libc syscall wrappers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyscallArgs {
+    pub name: &'static str, // Name of the libc function, e.g. "openat"
+    pub args: [usize; 6],   // Raw arguments, only as many as the function takes are meaningful
+}
+
+/// What an intercepted call returns to the library
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyscallResult {
+    Passthrough,   // Call the real libc function
+    Return(isize), // Return this value without calling anything
+    Error(c_int),  // Set errno to this value and return -1
+}
+
+type SyscallHandler = Box<dyn FnMut(&SyscallArgs) -> SyscallResult + Send>;
+
+// The wrappers are plain functions so they can't carry any context, there is one handler for the whole process
+static HANDLER: Mutex<Option<SyscallHandler>> = Mutex::new(None);
+
+pub(crate) fn set_handler(handler: Option<SyscallHandler>) {
+    *HANDLER.lock().unwrap_or_else(|e| e.into_inner()) = handler;
+}
+
+fn handle(args: &SyscallArgs) -> SyscallResult {
+    let Ok(mut handler) = HANDLER.lock() else {
+        return SyscallResult::Passthrough;
+    };
+    match handler.as_mut() {
+        Some(handler) => handler(args),
+        None => SyscallResult::Passthrough,
+    }
+}
+
+// Convert a handler's return value to the function's return type, values that don't fit become -1
+fn return_value<T: TryFrom<isize> + From<i8>>(value: isize) -> T {
+    T::try_from(value).unwrap_or(T::from(-1))
+}
+
+// Each wrapper takes the maximum number of register arguments, the library only sets the ones the real function
+// takes and the rest are ignored. The real function is called the same way, which also works for variadic open
+macro_rules! syscall_wrappers {
+    ($($name:ident -> $ret:ty;)*) => {
+        mod wrappers {
+            use super::*;
+            $(
+                pub(super) extern "C" fn $name(a0: usize, a1: usize, a2: usize, a3: usize, a4: usize, a5: usize) -> $ret {
+                    let args = SyscallArgs { name: stringify!($name), args: [a0, a1, a2, a3, a4, a5] };
+                    match handle(&args) {
+                        SyscallResult::Passthrough => {
+                            let real: extern "C" fn(usize, usize, usize, usize, usize, usize) -> $ret =
+                                unsafe { std::mem::transmute(libc::$name as *const ()) };
+                            real(a0, a1, a2, a3, a4, a5)
+                        },
+                        SyscallResult::Return(value) => return_value(value),
+                        SyscallResult::Error(errno) => {
+                            unsafe { *libc::__errno_location() = errno };
+                            -1
+                        },
+                    }
+                }
+            )*
+        }
+
+        // Name and wrapper of every intercepted function, for JNI::override_symbol
+        pub(crate) fn wrappers() -> Vec<(&'static str, *const ())> {
+            vec![$((stringify!($name), wrappers::$name as *const ()),)*]
+        }
+    };
+}
+
+syscall_wrappers! {
+    open -> c_int;
+    openat -> c_int;
+    close -> c_int;
+    read -> isize;
+    write -> isize;
+    pread -> isize;
+    pwrite -> isize;
+    lseek -> i64;
+    access -> c_int;
+    unlink -> c_int;
+    ioctl -> c_int;
+    socket -> c_int;
+    connect -> c_int;
+    bind -> c_int;
+    listen -> c_int;
+    accept -> c_int;
+    send -> isize;
+    recv -> isize;
+    sendto -> isize;
+    recvfrom -> isize;
+}
@@ -0,0 +1,103 @@
+use std::alloc::{alloc_zeroed, dealloc, Layout};
+
+use super::TlsInfo;
+
+// The resolver for static TLS descriptors, the same as glibc's _dl_tlsdesc_return. Code using the TLSDESC model
+// calls the descriptor's resolver with x0 (rax on x86_64) pointing at the descriptor and adds the result to the
+// thread pointer. It must preserve every other register, so it is written in assembly. The returned offset is the one
+// from initialization, so like the initial-exec model only the thread that initialized the library sees the right data
+#[cfg(target_arch = "x86_64")]
+std::arch::global_asm!(
+    ".text",
+    ".p2align 4",
+    ".hidden jni_loader_tlsdesc_return",
+    ".type jni_loader_tlsdesc_return, @function",
+    "jni_loader_tlsdesc_return:",
+    "mov rax, [rax + 8]",
+    "ret",
+    ".size jni_loader_tlsdesc_return, . - jni_loader_tlsdesc_return",
+);
+
+#[cfg(target_arch = "aarch64")]
+std::arch::global_asm!(
+    ".text",
+    ".p2align 2",
+    ".hidden jni_loader_tlsdesc_return",
+    ".type jni_loader_tlsdesc_return, %function",
+    "jni_loader_tlsdesc_return:",
+    "hint #34", // bti c
+    "ldr x0, [x0, 8]",
+    "ret",
+    ".size jni_loader_tlsdesc_return, . - jni_loader_tlsdesc_return",
+);
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+extern "C" {
+    fn jni_loader_tlsdesc_return();
+}
+
+// Address of the resolver to put in the first word of a TLS descriptor
+pub(crate) fn descriptor_resolver() -> usize {
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    let resolver = jni_loader_tlsdesc_return as usize;
+    #[cfg(all(not(target_arch = "x86_64"), not(target_arch = "aarch64")))]
+    let resolver = 0;
+    resolver
+}
+
+// The library's TLS block for the initial-exec model. Initial-exec code finds its variables at a fixed offset from
+// the thread pointer, so the offsets written during relocation are only correct for the thread that initialized the
+// library. Other threads that touch these variables will read or write unrelated memory
+pub(crate) struct TlsBlock {
+    base: usize,
+    layout: Layout,
+    thread_pointer: usize,
+}
+
+impl TlsBlock {
+    pub fn new(template: &[u8], info: TlsInfo) -> Option<Self> {
+        let layout = Layout::from_size_align(info.total_size.max(1), info.alignment.max(1)).ok()?;
+        let base = unsafe { alloc_zeroed(layout) };
+        if base.is_null() {
+            return None;
+        }
+        // Anything after the template is zero-initialized (.tbss)
+        unsafe { std::ptr::copy_nonoverlapping(template.as_ptr(), base, template.len().min(info.template_size)) };
+        Some(Self { base: base as usize, layout, thread_pointer: thread_pointer() })
+    }
+
+    // What a TP-relative relocation adds to a variable's offset within the block
+    pub fn thread_pointer_offset(&self) -> i64 {
+        self.base as i64 - self.thread_pointer as i64
+    }
+
+    // Where the variable at `offset` in the block is for the current thread. Only the thread that initialized the
+    // library has the block, the library would reach unrelated memory from any other thread
+    pub fn variable_address(&self, offset: usize) -> Option<usize> {
+        (thread_pointer() == self.thread_pointer).then_some(self.base + offset)
+    }
+}
+
+impl Drop for TlsBlock {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.base as *mut u8, self.layout) };
+    }
+}
+
+fn thread_pointer() -> usize {
+    #[cfg(target_arch = "x86_64")]
+    let thread_pointer = {
+        let thread_pointer: usize;
+        unsafe { std::arch::asm!("mov {}, fs:0", out(reg) thread_pointer, options(nostack, readonly)) };
+        thread_pointer
+    };
+    #[cfg(target_arch = "aarch64")]
+    let thread_pointer = {
+        let thread_pointer: usize;
+        unsafe { std::arch::asm!("mrs {}, tpidr_el0", out(reg) thread_pointer, options(nostack, nomem)) };
+        thread_pointer
+    };
+    #[cfg(all(not(target_arch = "x86_64"), not(target_arch = "aarch64")))]
+    let thread_pointer: usize = panic!("Unhandled system architecture");
+    thread_pointer
+}
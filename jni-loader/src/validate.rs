@@ -0,0 +1,123 @@
+use std::io::{Read, Seek};
+
+use elf::{
+    abi::{DT_GNU_HASH, DT_HASH, DT_STRSZ, DT_STRTAB, DT_SYMTAB, PT_DYNAMIC, PT_LOAD, SHT_NOBITS},
+    endian::AnyEndian,
+    segment::ProgramHeader,
+    ElfStream,
+};
+
+use crate::{dynamic::DynamicTables, mmap::system_page_size};
+
+// Reject files whose headers don't describe something that can be safely mapped. Every offset and size read from
+// the file is checked against the file itself, so crafted input fails here instead of while mapping or relocating
+pub(crate) fn validate<S: Read + Seek>(elf_file: &mut ElfStream<AnyEndian, S>, file_size: u64) -> Result<(), String> {
+    let ehdr = elf_file.ehdr;
+    let program_headers_end = ehdr.e_phnum as u64 * ehdr.e_phentsize as u64;
+    if ehdr.e_phnum == 0 || !within_file(ehdr.e_phoff, program_headers_end, file_size) {
+        return Err(format!("program headers at {:#x} are outside the file", ehdr.e_phoff));
+    }
+    let section_headers_end = ehdr.e_shnum as u64 * ehdr.e_shentsize as u64;
+    if ehdr.e_shnum != 0 && !within_file(ehdr.e_shoff, section_headers_end, file_size) {
+        return Err(format!("section headers at {:#x} are outside the file", ehdr.e_shoff));
+    }
+
+    let mut load_count = 0;
+    let page_size = system_page_size() as u64;
+    for (index, segment) in elf_file.segments().iter().enumerate() {
+        if !within_file(segment.p_offset, segment.p_filesz, file_size) {
+            return Err(format!("segment {index} is outside the file"));
+        }
+        if segment.p_type != PT_LOAD {
+            continue;
+        }
+        if segment.p_filesz > segment.p_memsz {
+            return Err(format!("segment {index} has more file data than memory"));
+        }
+        // The end is rounded up to a page when mapping, so that must fit in an address too
+        let fits_in_memory = |size: u64| {
+            segment
+                .p_vaddr
+                .checked_add(size)
+                .and_then(|end| end.checked_add(page_size))
+                .is_some_and(|end| usize::try_from(end).is_ok())
+        };
+        if !fits_in_memory(segment.p_filesz) || !fits_in_memory(segment.p_memsz) {
+            return Err(format!("segment {index} at {:#x} doesn't fit in the address space", segment.p_vaddr));
+        }
+        if segment.p_align > 1 && !segment.p_align.is_power_of_two() {
+            return Err(format!("segment {index} has an invalid alignment {:#x}", segment.p_align));
+        }
+        load_count += 1;
+    }
+    if load_count == 0 {
+        return Err("no loadable segments".to_string());
+    }
+    if !elf_file.segments().iter().any(|s| s.p_type == PT_DYNAMIC) {
+        return Err("no dynamic segment".to_string());
+    }
+
+    for (index, section) in elf_file.section_headers().iter().enumerate() {
+        if section.sh_type == SHT_NOBITS {
+            continue;
+        }
+        if !within_file(section.sh_offset, section.sh_size, file_size) {
+            return Err(format!("section {index} is outside the file"));
+        }
+    }
+
+    let Some(dynamic_section) = elf_file.dynamic().map_err(|e| e.to_string())? else {
+        return Err("failed to parse the dynamic section".to_string());
+    };
+    let entries: Vec<(i64, u64)> = dynamic_section.iter().map(|entry| (entry.d_tag, entry.d_val())).collect();
+    // Tables .dynamic points at are read straight out of the segment data, so they have to be inside it
+    let load_segments: Vec<ProgramHeader> =
+        elf_file.segments().iter().filter(|s| s.p_type == PT_LOAD).copied().collect();
+    let strings_size = entries.iter().find(|(tag, _)| *tag == DT_STRSZ).map_or(0, |&(_, size)| size);
+    for (tag, name, size) in [
+        (DT_STRTAB, "DT_STRTAB", strings_size),
+        (DT_SYMTAB, "DT_SYMTAB", 0),
+        (DT_HASH, "DT_HASH", 0),
+        (DT_GNU_HASH, "DT_GNU_HASH", 0),
+    ] {
+        for &(_, addr) in entries.iter().filter(|(d_tag, _)| *d_tag == tag) {
+            if !within_load_segment(&load_segments, addr, size) {
+                return Err(format!("{name} at {addr:#x} is outside the loadable segments"));
+            }
+        }
+    }
+    // Stripped libraries only describe their symbol tables in .dynamic
+    if let Some(dynamic_tables) = DynamicTables::read(elf_file).map_err(|e| e.to_string())? {
+        if dynamic_tables.gnu_hash.is_none() && dynamic_tables.sysv_hash.is_none() {
+            return Err("missing a symbol hash table".to_string());
+        }
+        return Ok(());
+    }
+    for name in [".dynsym", ".dynstr"] {
+        if elf_file.section_header_by_name(name).map_err(|e| e.to_string())?.is_none() {
+            return Err(format!("missing {name}"));
+        }
+    }
+    let has_gnu_hash = elf_file.section_header_by_name(".gnu.hash").map_err(|e| e.to_string())?.is_some();
+    let has_hash = elf_file.section_header_by_name(".hash").map_err(|e| e.to_string())?.is_some();
+    if !has_gnu_hash && !has_hash {
+        return Err("missing a symbol hash table".to_string());
+    }
+
+    Ok(())
+}
+
+fn within_file(offset: u64, size: u64, file_size: u64) -> bool {
+    offset.checked_add(size).is_some_and(|end| end <= file_size)
+}
+
+// Whether a table starting at `addr` that is at least `size` bytes long starts and ends in the file data of a segment
+fn within_load_segment(load_segments: &[ProgramHeader], addr: u64, size: u64) -> bool {
+    let Some(end) = addr.checked_add(size) else {
+        return false;
+    };
+    load_segments.iter().any(|s| {
+        let segment_end = s.p_vaddr.checked_add(s.p_filesz);
+        s.p_vaddr <= addr && segment_end.is_some_and(|segment_end| addr < segment_end && end <= segment_end)
+    })
+}